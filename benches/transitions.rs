@@ -0,0 +1,28 @@
+//! Measures the throughput of transitions. Run this with and without the `timing` feature to see
+//! what reading the clock costs.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use plain_state_machine_hype_train::*;
+
+fn transitions(c: &mut Criterion) {
+    let name = if cfg!(feature = "timing") {
+        "ready and store with timing"
+    } else {
+        "ready and store without timing"
+    };
+    c.bench_function(name, |b| {
+        b.iter_batched(
+            || State::stored_with(0),
+            |state| {
+                state
+                    .ready()
+                    .and_then(State::store)
+                    .expect("should be able to transition from stored to ready to stored")
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, transitions);
+criterion_main!(benches);
@@ -0,0 +1,209 @@
+//! The transition logic of the stored, ready and paused states without `std`, `tracing` or
+//! `Instant`, for targets like embedded devices. Time is supplied by a `Ticks` implementation and
+//! nothing is logged. The ready count is threaded through the transitions exactly like it is by
+//! `State`.
+
+use crate::*;
+
+/// Supplies the current time to a `BareState`, as a monotonic number of ticks in whatever unit
+/// the target provides.
+pub trait Ticks {
+    fn now(&self) -> u64;
+}
+
+/// The states that a `BareState` can be in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BareKind {
+    Stored,
+    Ready,
+    Paused,
+}
+
+impl BareKind {
+    /// The same names as returned by `State::name`.
+    pub fn name(self) -> &'static str {
+        match self {
+            BareKind::Stored => "stored",
+            BareKind::Ready => "ready",
+            BareKind::Paused => "paused",
+        }
+    }
+}
+
+/// A state machine that starts out stored. Rejected transitions hand back the unchanged state in
+/// the `Err` variant.
+#[derive(Debug)]
+pub struct BareState<C> {
+    kind: BareKind,
+    ready_count: u64,
+    transition_count: u64,
+    config: Config,
+    entered_at: u64,
+    clock: C,
+}
+
+impl<C: Ticks> BareState<C> {
+    /// Starts in the stored state with a ready count of 0.
+    pub fn new(config: Config, clock: C) -> Result<Self, EnterError> {
+        config.validate()?;
+
+        Ok(Self {
+            kind: BareKind::Stored,
+            ready_count: 0,
+            transition_count: 0,
+            config,
+            entered_at: clock.now(),
+            clock,
+        })
+    }
+
+    pub fn kind(&self) -> BareKind {
+        self.kind
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.kind.name()
+    }
+
+    pub fn ready_count(&self) -> u64 {
+        self.ready_count
+    }
+
+    pub fn transition_count(&self) -> u64 {
+        self.transition_count
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// How many ticks we have been in the current state so far.
+    pub fn elapsed(&self) -> u64 {
+        self.clock.now().saturating_sub(self.entered_at)
+    }
+
+    /// Like `State::ready`, this is rejected when it would exceed `max_ready_count` or overflow in
+    /// `OverflowMode::Error`.
+    pub fn ready(self) -> Result<Self, Self> {
+        if self.kind != BareKind::Stored {
+            return Err(self);
+        }
+        match self.config.next_ready_count(self.ready_count) {
+            Some(ready_count) => Ok(self.enter(BareKind::Ready, ready_count)),
+            None => Err(self),
+        }
+    }
+
+    pub fn store(self) -> Result<Self, Self> {
+        match self.kind {
+            BareKind::Ready | BareKind::Paused => {
+                let ready_count = self.ready_count;
+                Ok(self.enter(BareKind::Stored, ready_count))
+            }
+            BareKind::Stored => Err(self),
+        }
+    }
+
+    pub fn pause(self) -> Result<Self, Self> {
+        if self.kind != BareKind::Ready {
+            return Err(self);
+        }
+        let ready_count = self.ready_count;
+        Ok(self.enter(BareKind::Paused, ready_count))
+    }
+
+    /// Entering the ready state again increments the ready count, so like `ready` this is rejected
+    /// when the increment would exceed `max_ready_count` or overflow in `OverflowMode::Error`.
+    pub fn resume(self) -> Result<Self, Self> {
+        if self.kind != BareKind::Paused {
+            return Err(self);
+        }
+        match self.config.next_ready_count(self.ready_count) {
+            Some(ready_count) => Ok(self.enter(BareKind::Ready, ready_count)),
+            None => Err(self),
+        }
+    }
+
+    fn enter(self, kind: BareKind, ready_count: u64) -> Self {
+        Self {
+            kind,
+            ready_count,
+            transition_count: self.transition_count.saturating_add(1),
+            entered_at: self.clock.now(),
+            ..self
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[derive(Debug)]
+    struct ManualTicks<'a>(&'a Cell<u64>);
+
+    impl Ticks for ManualTicks<'_> {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn matches_state() {
+        let ticks = Cell::new(0);
+        let bare = BareState::new(Config::default(), ManualTicks(&ticks))
+            .unwrap()
+            .ready()
+            .expect("should be able to transition from stored to ready")
+            .pause()
+            .expect("should be able to transition from ready to paused")
+            .resume()
+            .expect("should be able to transition from paused to ready")
+            .store()
+            .expect("should be able to transition from ready to stored");
+        let state = State::stored_with(0)
+            .ready()
+            .and_then(State::pause)
+            .and_then(State::resume)
+            .and_then(State::store)
+            .unwrap();
+        assert_eq!(state.name(), bare.name());
+        assert_eq!(state.ready_count(), bare.ready_count());
+        assert_eq!(state.transition_count(), bare.transition_count());
+
+        let bare = bare
+            .store()
+            .expect_err("can not transition from stored to stored");
+        ticks.set(5);
+        assert_eq!(5, bare.elapsed());
+    }
+
+    #[test]
+    fn ready_respects_max_ready_count() {
+        let config = Config {
+            max_ready_count: Some(1),
+            ..Config::default()
+        };
+        let ticks = Cell::new(0);
+        let bare = BareState::new(config.clone(), ManualTicks(&ticks))
+            .unwrap()
+            .ready()
+            .and_then(BareState::store)
+            .unwrap()
+            .ready()
+            .expect_err("the ready count should not exceed the maximum");
+        assert_eq!(BareKind::Stored, bare.kind());
+        assert_eq!(1, bare.ready_count());
+
+        let bare = BareState::new(config, ManualTicks(&ticks))
+            .unwrap()
+            .ready()
+            .and_then(BareState::pause)
+            .unwrap()
+            .resume()
+            .expect_err("resuming should not exceed the maximum either");
+        assert_eq!(BareKind::Paused, bare.kind());
+        assert_eq!(1, bare.ready_count());
+    }
+}
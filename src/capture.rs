@@ -0,0 +1,93 @@
+//! A tracing layer that records what happened so tests can make assertions about it.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    SpanOpened(&'static str),
+    SpanClosed(&'static str),
+    /// The level and fields of an event. The fields are `(name, value)` pairs, with the values
+    /// formatted using `Debug`. The message is recorded as a field named `message`.
+    Event(Level, Vec<(&'static str, String)>),
+}
+
+impl Record {
+    pub fn is_span(&self) -> bool {
+        matches!(self, Record::SpanOpened(_) | Record::SpanClosed(_))
+    }
+
+    /// Looks up the formatted value of the field with the given name of an event.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        match self {
+            Record::Event(_, fields) => fields
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| value.as_str()),
+            _ => None,
+        }
+    }
+
+    /// The level of an event.
+    pub fn level(&self) -> Option<Level> {
+        match self {
+            Record::Event(level, _) => Some(*level),
+            _ => None,
+        }
+    }
+}
+
+struct FieldVisitor(Vec<(&'static str, String)>);
+
+impl Visit for FieldVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name(), format!("{value:?}")));
+    }
+}
+
+#[derive(Default)]
+struct CaptureLayer {
+    records: Arc<Mutex<Vec<Record>>>,
+}
+
+impl<S> Layer<S> for CaptureLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, _id: &span::Id, _ctx: Context<'_, S>) {
+        let name = attrs.metadata().name();
+        self.records.lock().unwrap().push(Record::SpanOpened(name));
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor(Vec::new());
+        event.record(&mut visitor);
+        self.records
+            .lock()
+            .unwrap()
+            .push(Record::Event(*event.metadata().level(), visitor.0));
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            let name = span.metadata().name();
+            self.records.lock().unwrap().push(Record::SpanClosed(name));
+        }
+    }
+}
+
+/// Runs `f` with a subscriber that captures all spans and events on the current thread.
+pub fn capture<R>(f: impl FnOnce() -> R) -> (R, Vec<Record>) {
+    let layer = CaptureLayer::default();
+    let records = Arc::clone(&layer.records);
+    let subscriber = tracing_subscriber::registry().with(layer);
+    let result = tracing::subscriber::with_default(subscriber, f);
+    let records = records.lock().unwrap().clone();
+    (result, records)
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The source of time for the states. Swapping out the clock makes it possible to control time in
+/// tests.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The time as recorded by the states, which only reads `now` with the `timing` feature.
+    fn timestamp(&self) -> Timestamp {
+        #[cfg(feature = "timing")]
+        return self.now();
+        #[cfg(not(feature = "timing"))]
+        Timestamp
+    }
+}
+
+/// When a state was entered. Without the `timing` feature this is a zero-sized marker instead of
+/// an `Instant`, the clock is never read, and the time spent in every state is zero.
+#[cfg(feature = "timing")]
+pub type Timestamp = Instant;
+
+/// A zero-sized stand-in for `Instant` when the `timing` feature is disabled.
+#[cfg(not(feature = "timing"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp;
+
+#[cfg(not(feature = "timing"))]
+impl Timestamp {
+    pub fn now() -> Self {
+        Timestamp
+    }
+
+    pub fn saturating_duration_since(&self, _earlier: Timestamp) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Reads the time from the operating system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Reads the time from tokio, which follows the virtual time of a runtime whose clock is paused,
+/// like in `#[tokio::test(start_paused = true)]`, and is the same as `SystemClock` otherwise. Use
+/// this when the timers that act on the states are tokio timers.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+}
+
+/// A clock that only moves forward when it is told to. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    base: Instant,
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            base: Instant::now(),
+            offset: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.base + *self.offset.lock().unwrap()
+    }
+}
+
+/// The clock used when none is specified.
+pub fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// The clock used by `run`.
+pub fn tokio_clock() -> Arc<dyn Clock> {
+    Arc::new(TokioClock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_only_moves_when_advanced() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        assert_eq!(start, clock.now());
+        clock.clone().advance(Duration::from_secs(3));
+        assert_eq!(Duration::from_secs(3), clock.now() - start);
+    }
+}
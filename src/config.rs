@@ -0,0 +1,216 @@
+use crate::*;
+use core::time::Duration;
+
+/// The tunables of the state machine. The configuration is passed into `enter` and carried forward
+/// through all transitions, so it only has to be provided once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Config {
+    /// The highest ready count that `State::ready` may reach, if limited.
+    pub max_ready_count: Option<u64>,
+    /// How long the ready state may stay idle before `poll_timeout` stores it, if at all. This
+    /// never happens without the `timing` feature, because no time passes in any state then.
+    pub auto_store_after: Option<Duration>,
+    /// What happens when incrementing the ready count would overflow.
+    pub overflow_mode: OverflowMode,
+    /// How much the ready count is incremented by when entering the ready state.
+    pub step: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Config {
+    pub const DEFAULT: Config = Config {
+        max_ready_count: None,
+        auto_store_after: None,
+        overflow_mode: OverflowMode::Saturate,
+        step: 1,
+    };
+
+    /// Starts building a configuration from the defaults.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Rejects configurations that make no sense, like a `step` of 0.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_ready_count == Some(0) {
+            return Err(ConfigError::ZeroMaxReadyCount);
+        }
+        if self.step == 0 {
+            return Err(ConfigError::ZeroStep);
+        }
+        if let Some(max_ready_count) = self.max_ready_count {
+            if max_ready_count < self.step {
+                return Err(ConfigError::MaxReadyCountBelowStep {
+                    max_ready_count,
+                    step: self.step,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The ready count after entering the ready state with the given ready count, or `None` if
+    /// doing so would exceed `max_ready_count` or overflow in `OverflowMode::Error`.
+    pub fn next_ready_count(&self, ready_count: u64) -> Option<u64> {
+        self.overflow_mode
+            .increment(ready_count, self.step)
+            .filter(|&next| self.max_ready_count.is_none_or(|max| next <= max))
+    }
+}
+
+/// Builds a `Config` that is validated before it is handed out. Tunables that are not set keep
+/// their default.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn max_ready_count(mut self, max_ready_count: u64) -> Self {
+        self.config.max_ready_count = Some(max_ready_count);
+        self
+    }
+
+    pub fn step(mut self, step: u64) -> Self {
+        self.config.step = step;
+        self
+    }
+
+    pub fn auto_store_after(mut self, auto_store_after: Duration) -> Self {
+        self.config.auto_store_after = Some(auto_store_after);
+        self
+    }
+
+    pub fn overflow_mode(mut self, overflow_mode: OverflowMode) -> Self {
+        self.config.overflow_mode = overflow_mode;
+        self
+    }
+
+    /// See `Config::validate`.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_survives_transitions() {
+        let config = Config {
+            max_ready_count: Some(10),
+            auto_store_after: Some(Duration::from_secs(60)),
+            overflow_mode: OverflowMode::Wrap,
+            step: 2,
+        };
+        let state = State::default_with(config.clone())
+            .ready()
+            .expect("should be able to transition from stored to ready")
+            .store()
+            .expect("should be able to transition from ready to stored");
+        assert_eq!(&config, state.config());
+        assert_eq!(2, state.ready_count());
+    }
+
+    #[test]
+    fn try_enter_validates_config() {
+        assert!(StoredState::try_enter(StoredStateInputs::new(0)).is_ok());
+
+        let inputs = |config| StoredStateInputs {
+            config,
+            ..StoredStateInputs::new(0)
+        };
+        let error = StoredState::try_enter(inputs(Config {
+            max_ready_count: Some(0),
+            ..Config::default()
+        }))
+        .expect_err("a max_ready_count of 0 is invalid");
+        assert_eq!(
+            EnterError::InvalidConfig(ConfigError::ZeroMaxReadyCount),
+            error
+        );
+
+        let error = ReadyState::try_enter(ReadyStateInputs {
+            config: Config {
+                step: 0,
+                ..Config::default()
+            },
+            ..ReadyStateInputs::new(0)
+        })
+        .expect_err("a step of 0 is invalid");
+        assert_eq!(EnterError::InvalidConfig(ConfigError::ZeroStep), error);
+        assert_eq!(
+            "invalid configuration: step must be greater than 0",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn next_ready_count_respects_max() {
+        let config = Config {
+            max_ready_count: Some(3),
+            ..Config::default()
+        };
+        assert_eq!(Some(3), config.next_ready_count(2));
+        assert_eq!(None, config.next_ready_count(3));
+    }
+
+    #[test]
+    fn builder_builds_valid_config() {
+        let config = Config::builder()
+            .max_ready_count(10)
+            .step(2)
+            .auto_store_after(Duration::from_secs(60))
+            .overflow_mode(OverflowMode::Wrap)
+            .build()
+            .expect("the config should be valid");
+        assert_eq!(
+            Config {
+                max_ready_count: Some(10),
+                auto_store_after: Some(Duration::from_secs(60)),
+                overflow_mode: OverflowMode::Wrap,
+                step: 2,
+            },
+            config
+        );
+        assert_eq!(Ok(Config::default()), Config::builder().build());
+    }
+
+    #[test]
+    fn builder_rejects_invalid_config() {
+        assert_eq!(
+            Err(ConfigError::ZeroStep),
+            Config::builder().step(0).build()
+        );
+        assert_eq!(
+            Err(ConfigError::ZeroMaxReadyCount),
+            Config::builder().max_ready_count(0).build()
+        );
+        let error = Config::builder()
+            .max_ready_count(2)
+            .step(3)
+            .build()
+            .expect_err("the maximum should not be below the step");
+        assert_eq!(
+            ConfigError::MaxReadyCountBelowStep {
+                max_ready_count: 2,
+                step: 3,
+            },
+            error
+        );
+        assert_eq!(
+            "max_ready_count must be at least the step, but 2 is less than 3",
+            error.to_string()
+        );
+    }
+}
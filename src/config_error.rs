@@ -0,0 +1,31 @@
+use core::fmt;
+
+/// Describes why `Config::validate` rejected a configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A `max_ready_count` of 0 would make the ready state unreachable.
+    ZeroMaxReadyCount,
+    /// A `step` of 0 would never increment the ready count.
+    ZeroStep,
+    /// A `max_ready_count` below the `step` would make the ready state unreachable from a ready
+    /// count of 0.
+    MaxReadyCountBelowStep { max_ready_count: u64, step: u64 },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ZeroMaxReadyCount => write!(f, "max_ready_count must be greater than 0"),
+            ConfigError::ZeroStep => write!(f, "step must be greater than 0"),
+            ConfigError::MaxReadyCountBelowStep {
+                max_ready_count,
+                step,
+            } => write!(
+                f,
+                "max_ready_count must be at least the step, but {max_ready_count} is less than {step}"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for ConfigError {}
@@ -0,0 +1,220 @@
+/// Declares the states of a state machine and the transitions between them in one block.
+///
+/// ```text
+/// state_machine! {
+///     pub enum State {
+///         Stored(StoredState) as "stored" { ready => Ready }
+///             -> StoredStateTransitionResult { Ready(ReadyState) },
+///         Ready(ReadyState) as "ready" { store => Stored }
+///             -> ReadyStateTransitionResult { Stored(StoredState) },
+///     }
+/// }
+/// ```
+///
+/// This generates the enum with one variant per state, `From` conversions from every state into
+/// the enum, the transition result type of every state through `impl_state_transition_result!`,
+/// and the `name`, `available_operations` and `apply` methods. Each listed operation must be a
+/// method on the state that consumes it and returns its transition result. The target after `=>`
+/// documents where the operation leads and is checked to be one of the declared variants.
+///
+/// That is all it generates. The state structs are not generated because their fields and
+/// `enter`/`exit` methods differ from state to state, so they have to be written by hand like the
+/// ones in this crate. The operations of a declared machine are the identifiers in its block
+/// rather than the variants of `Operation`, which is why `apply` takes an operation name.
+///
+/// The crate's own `State` does not use this macro. Its transitions are guarded, for example by
+/// `max_ready_count` and by whether an error is recoverable, and the macro can only declare
+/// transitions that always succeed.
+///
+/// This needs the `std` feature because the generated `apply` returns a `TransitionError`.
+#[cfg(feature = "std")]
+#[macro_export]
+macro_rules! state_machine {
+    (pub enum $Machine: ident {
+        $($Variant: ident ($State: ty) as $name: literal {
+            $($op: ident => $Target: ident),* $(,)?
+        } -> $TransitionResult: ident {
+            $($ResultVariant: ident ($ResultState: ty)),* $(,)?
+        }),* $(,)?
+    }) => {
+        #[derive(Debug)]
+        pub enum $Machine {
+            $($Variant($State)),*
+        }
+
+        $(
+            impl From<$State> for $Machine {
+                fn from(state: $State) -> Self {
+                    Self::$Variant(state)
+                }
+            }
+
+            $crate::impl_state_transition_result! {
+                pub enum $TransitionResult for $Machine {
+                    $($ResultVariant($ResultState)),*
+                }
+            }
+        )*
+
+        impl $Machine {
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Self::$Variant(_) => $name),*
+                }
+            }
+
+            /// The names of the operations that are legal from the current state.
+            pub fn available_operations(&self) -> &'static [&'static str] {
+                match self {
+                    $(Self::$Variant(_) => &[$(stringify!($op)),*]),*
+                }
+            }
+
+            /// Dispatches to the transition method called `op`. Operations that are not legal from
+            /// the current state hand back the unchanged state.
+            pub fn apply(
+                self,
+                op: &'static str,
+            ) -> Result<Self, (Self, $crate::TransitionError)> {
+                // Refers to every target so that a typo in a target fails to compile.
+                #[allow(dead_code)]
+                fn check_targets(state: &$Machine) {
+                    $($(let _ = matches!(state, $Machine::$Target(_));)*)*
+                }
+
+                match self {
+                    $(Self::$Variant(state) => match op {
+                        $(stringify!($op) => {
+                            let result: $TransitionResult = state.$op();
+                            Ok(Self::from(result))
+                        })*
+                        _ => {
+                            let state = Self::$Variant(state);
+                            let error = $crate::TransitionError::Illegal {
+                                operation: op,
+                                state: state.name(),
+                                valid_operations: state.available_operations(),
+                            };
+                            Err((state, error))
+                        }
+                    }),*
+                }
+            }
+        }
+    };
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::*;
+
+    mod two_states {
+        #[derive(Debug)]
+        pub struct StoredState {
+            pub ready_count: u64,
+        }
+
+        #[derive(Debug)]
+        pub struct ReadyState {
+            pub ready_count: u64,
+        }
+
+        impl StoredState {
+            pub fn ready(self) -> StoredStateTransitionResult {
+                StoredStateTransitionResult::Ready(ReadyState {
+                    ready_count: self.ready_count + 1,
+                })
+            }
+        }
+
+        impl ReadyState {
+            pub fn store(self) -> ReadyStateTransitionResult {
+                ReadyStateTransitionResult::Stored(StoredState {
+                    ready_count: self.ready_count,
+                })
+            }
+        }
+
+        state_machine! {
+            pub enum State {
+                Stored(StoredState) as "stored" { ready => Ready }
+                    -> StoredStateTransitionResult { Ready(ReadyState) },
+                Ready(ReadyState) as "ready" { store => Stored }
+                    -> ReadyStateTransitionResult { Stored(StoredState) },
+            }
+        }
+
+        impl State {
+            pub fn ready_count(&self) -> u64 {
+                match self {
+                    State::Stored(state) => state.ready_count,
+                    State::Ready(state) => state.ready_count,
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn generated_machine_matches_stored_and_ready() {
+        let state = two_states::State::from(two_states::StoredState { ready_count: 0 });
+        assert_eq!("stored", state.name());
+        assert_eq!(["ready"], state.available_operations());
+
+        let state = state
+            .apply("ready")
+            .expect("should be able to transition from stored to ready");
+        assert_eq!("ready", state.name());
+        assert_eq!(["store"], state.available_operations());
+        assert_eq!(1, state.ready_count());
+
+        let (state, error) = state
+            .apply("pause")
+            .expect_err("the two state machine can not pause");
+        assert_eq!(
+            TransitionError::Illegal {
+                operation: "pause",
+                state: "ready",
+                valid_operations: &["store"],
+            },
+            error
+        );
+
+        let state = state
+            .apply("store")
+            .expect("should be able to transition from ready to stored");
+        assert_eq!("stored", state.name());
+        assert_eq!(1, state.ready_count());
+    }
+
+    #[test]
+    fn generated_transition_results_convert() {
+        use two_states::*;
+
+        let state = StoredStateTransitionResult::Ready(ReadyState { ready_count: 1 })
+            .try_into_state()
+            .expect("variants without a guard always convert");
+        assert_eq!("ready", state.name());
+        let state = ReadyStateTransitionResult::try_from(state)
+            .expect_err("a ready state is not a result of storing");
+        let StoredStateTransitionResult::Ready(state) =
+            StoredStateTransitionResult::try_from(state)
+                .expect("a ready state is a result of readying");
+        let state = state
+            .store()
+            .try_into_state()
+            .expect("variants without a guard always convert");
+        assert_eq!("stored", state.name());
+    }
+
+    #[test]
+    fn generated_names_match_the_real_machine() {
+        let stored = State::stored_with(0);
+        assert_eq!(stored.available_operations(), ["ready"]);
+        let generated = two_states::State::from(two_states::StoredState { ready_count: 0 });
+        assert_eq!(stored.name(), generated.name());
+        assert_eq!(
+            stored.available_operations(),
+            generated.available_operations()
+        );
+    }
+}
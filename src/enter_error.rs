@@ -0,0 +1,31 @@
+use crate::ConfigError;
+use core::fmt;
+
+/// Describes why the inputs to `try_enter` were rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnterError {
+    /// The configuration did not pass `Config::validate`.
+    InvalidConfig(ConfigError),
+}
+
+impl From<ConfigError> for EnterError {
+    fn from(error: ConfigError) -> Self {
+        EnterError::InvalidConfig(error)
+    }
+}
+
+impl fmt::Display for EnterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnterError::InvalidConfig(error) => write!(f, "invalid configuration: {error}"),
+        }
+    }
+}
+
+impl core::error::Error for EnterError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            EnterError::InvalidConfig(error) => Some(error),
+        }
+    }
+}
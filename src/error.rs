@@ -0,0 +1,383 @@
+use crate::*;
+pub use internal::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Span;
+
+mod internal {
+    use super::*;
+
+    #[derive(Debug)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    pub struct ErrorState {
+        ready_count: u64,
+        transition_count: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        overflowed: bool,
+        #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+        clock: Arc<dyn Clock>,
+        #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+        logger: Arc<dyn StateLogger>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        transform: Option<Arc<dyn TransitionTransform>>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        config: Config,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        last_request_id: Option<u64>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        previous: Option<&'static str>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        time_in_previous: Duration,
+        reason: String,
+        recoverable: bool,
+        #[cfg_attr(feature = "serde", serde(skip, default = "Timestamp::now"))]
+        error_start: Timestamp,
+        #[cfg_attr(feature = "serde", serde(skip, default = "Span::none"))]
+        span: Span,
+    }
+
+    impl ErrorState {
+        /// Panics if the inputs are invalid, see `try_enter`.
+        pub fn enter(inputs: ErrorStateInputs) -> Self {
+            Self::try_enter(inputs).expect("the inputs should be valid")
+        }
+
+        /// Like `enter`, but rejects invalid inputs instead of panicking.
+        pub fn try_enter(inputs: ErrorStateInputs) -> Result<Self, EnterError> {
+            inputs.config.validate()?;
+
+            let ErrorStateInputs {
+                ready_count,
+                transition_count,
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                from,
+                time_in_previous,
+                reason,
+                recoverable,
+            } = inputs;
+
+            let CarriedData {
+                ready_count,
+                last_request_id,
+            } = CarriedData {
+                ready_count,
+                last_request_id,
+            }
+            .transformed(transform.as_deref(), from, StateKind::Error, &config);
+
+            let state = Self {
+                span: tracing::info_span!(
+                    "error",
+                    ready_count,
+                    last_request_id = tracing::field::Empty,
+                    reason,
+                    recoverable
+                ),
+                ready_count,
+                transition_count,
+                overflowed,
+                reason,
+                recoverable,
+                error_start: clock.timestamp(),
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                previous: from,
+                time_in_previous,
+            };
+            state.log_entered();
+            Ok(state)
+        }
+
+        fn log_entered(&self) {
+            self.span
+                .in_scope(|| self.logger.state_entered("error", self.ready_count));
+        }
+
+        pub fn exit(self) -> ErrorStateOutputs {
+            let Self {
+                ready_count,
+                transition_count,
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                previous: _,
+                time_in_previous: _,
+                reason: _,
+                recoverable: _,
+                error_start,
+                span,
+            } = self;
+
+            let time_in_state = clock.timestamp().saturating_duration_since(error_start);
+            span.record("last_request_id", last_request_id);
+            span.in_scope(|| logger.state_exited("error", time_in_state, ready_count));
+
+            ErrorStateOutputs {
+                ready_count,
+                transition_count: transition_count.saturating_add(1),
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                time_in_state,
+            }
+        }
+
+        pub fn ready_count(&self) -> u64 {
+            self.ready_count
+        }
+
+        pub fn transition_count(&self) -> u64 {
+            self.transition_count
+        }
+
+        pub fn overflowed(&self) -> bool {
+            self.overflowed
+        }
+
+        pub fn clock(&self) -> &Arc<dyn Clock> {
+            &self.clock
+        }
+
+        /// The configuration, which is carried forward through all transitions.
+        pub fn config(&self) -> &Config {
+            &self.config
+        }
+
+        /// The id of the last request that transitioned the state machine, if any.
+        pub fn last_request_id(&self) -> Option<u64> {
+            self.last_request_id
+        }
+
+        /// Tags the state with the id of the request that is about to transition it, so the id
+        /// shows up in the logs of `exit`.
+        pub(crate) fn set_last_request_id(&mut self, last_request_id: Option<u64>) {
+            self.last_request_id = last_request_id;
+        }
+
+        /// The name of the state we transitioned from to get here, or `None` if the state machine
+        /// started in this state.
+        pub fn previous(&self) -> Option<&'static str> {
+            self.previous
+        }
+
+        /// How long we spent in the previous state, or zero if there is none.
+        pub fn time_in_previous(&self) -> Duration {
+            self.time_in_previous
+        }
+
+        /// The span that is open while we are in the error state. Enter it to associate logs
+        /// with this state.
+        pub fn span(&self) -> &Span {
+            &self.span
+        }
+
+        /// When we entered the error state.
+        pub fn entered_at(&self) -> Timestamp {
+            self.error_start
+        }
+
+        /// How long we have been in the error state so far.
+        pub fn elapsed(&self) -> Duration {
+            self.clock
+                .timestamp()
+                .saturating_duration_since(self.error_start)
+        }
+
+        pub fn reason(&self) -> &str {
+            &self.reason
+        }
+
+        pub fn is_recoverable(&self) -> bool {
+            self.recoverable
+        }
+    }
+}
+
+/// This defines the inputs required to enter the ErrorState.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ErrorStateInputs {
+    pub ready_count: u64,
+    pub transition_count: u64,
+    /// Whether the ready count has ever overflowed, see `State::has_overflowed`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overflowed: bool,
+    #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+    pub clock: Arc<dyn Clock>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+    pub logger: Arc<dyn StateLogger>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub transform: Option<Arc<dyn TransitionTransform>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub config: Config,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_request_id: Option<u64>,
+    /// The name of the state we are transitioning from, if any.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub from: Option<&'static str>,
+    /// How long we spent in the state we are transitioning from.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub time_in_previous: Duration,
+    pub reason: String,
+    /// Whether the state machine is allowed to leave the error state through `recover`.
+    pub recoverable: bool,
+}
+
+/// This defines the outputs provided when leaving the ErrorState.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ErrorStateOutputs {
+    pub ready_count: u64,
+    pub transition_count: u64,
+    /// Whether the ready count has ever overflowed, see `State::has_overflowed`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overflowed: bool,
+    #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+    pub clock: Arc<dyn Clock>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+    pub logger: Arc<dyn StateLogger>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub transform: Option<Arc<dyn TransitionTransform>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub config: Config,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_request_id: Option<u64>,
+    /// How long we spent in the state that was exited.
+    pub time_in_state: Duration,
+}
+
+impl_state_transition_result! {
+    pub enum ErrorStateTransitionResult {
+        Stored(StoredState),
+    }
+}
+
+impl ErrorState {
+    /// An unrecoverable error has no outgoing transitions, in which case the state is handed back
+    /// without calling `exit`.
+    pub fn recover(self) -> Result<ErrorStateTransitionResult, Self> {
+        if !self.is_recoverable() {
+            return Err(self);
+        }
+
+        let ErrorStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
+
+        Ok(ErrorStateTransitionResult::Stored(StoredState::enter(
+            StoredStateInputs {
+                ready_count,
+                transition_count,
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                from: Some("error"),
+                time_in_previous: time_in_state,
+                now: None,
+            },
+        )))
+    }
+
+    /// Replaces the current error with a new one.
+    pub fn error(self, reason: String, recoverable: bool) -> ErrorState {
+        let ErrorStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
+
+        ErrorState::enter(ErrorStateInputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            from: Some("error"),
+            time_in_previous: time_in_state,
+            reason,
+            recoverable,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_state(recoverable: bool) -> ErrorState {
+        ErrorState::enter(ErrorStateInputs {
+            ready_count: 3,
+            transition_count: 0,
+            overflowed: false,
+            clock: system_clock(),
+            logger: tracing_logger(),
+            transform: None,
+            config: Config::default(),
+            from: None,
+            last_request_id: None,
+            time_in_previous: Duration::ZERO,
+            reason: "disk on fire".to_string(),
+            recoverable,
+        })
+    }
+
+    #[test]
+    fn recover_from_recoverable_error() {
+        let state = error_state(true);
+        assert_eq!("disk on fire", state.reason());
+        let state = state
+            .recover()
+            .expect("should be able to recover from a recoverable error");
+        let ErrorStateTransitionResult::Stored(state) = state;
+        assert_eq!(
+            3,
+            state.ready_count(),
+            "recovering should preserve the ready count"
+        );
+    }
+
+    #[test]
+    fn reject_recover_from_unrecoverable_error() {
+        let state = error_state(false);
+        let state = state
+            .recover()
+            .expect_err("should not be able to recover from an unrecoverable error");
+        assert_eq!(3, state.ready_count());
+        assert!(!state.is_recoverable());
+    }
+}
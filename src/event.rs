@@ -0,0 +1,15 @@
+use std::time::SystemTime;
+
+/// Describes a successful transition, for example for audit logging. With the `serde` feature
+/// enabled, each event serializes to a single JSON line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TransitionEvent {
+    /// The wall clock time at which the transition completed.
+    pub timestamp: SystemTime,
+    pub from: &'static str,
+    pub to: &'static str,
+    pub op: &'static str,
+    /// The ready count after the transition.
+    pub ready_count: u64,
+}
@@ -0,0 +1,43 @@
+use crate::*;
+use std::sync::Arc;
+
+/// Warns when the state that owns it is dropped without going through `exit`, for example while
+/// unwinding from a panic, because the log of the time spent in the state would be lost otherwise.
+/// The state itself can not implement `Drop` because `exit` moves its fields out.
+pub(crate) struct ExitGuard {
+    name: &'static str,
+    clock: Arc<dyn Clock>,
+    start: Timestamp,
+    armed: bool,
+}
+
+impl ExitGuard {
+    pub(crate) fn new(name: &'static str, clock: Arc<dyn Clock>, start: Timestamp) -> Self {
+        Self {
+            name,
+            clock,
+            start,
+            armed: true,
+        }
+    }
+
+    /// Called by `exit`, after which dropping the guard does nothing.
+    pub(crate) fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        if self.armed {
+            let elapsed = self.clock.timestamp().saturating_duration_since(self.start);
+            tracing::warn!(
+                state = self.name,
+                duration_ms = elapsed.as_millis(),
+                "Dropped {} state without exit after {}.",
+                self.name,
+                format_duration(elapsed)
+            );
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+/// Formats a duration for humans, with a unit that depends on its magnitude: `450µs`, `450ms`,
+/// `1.23s`, `3m 2s` or `1h 5m`. The smallest unit shown is truncated rather than rounded.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if d < Duration::from_millis(1) {
+        format!("{}µs", d.as_micros())
+    } else if d < Duration::from_secs(1) {
+        format!("{}ms", d.as_millis())
+    } else if secs < 60 {
+        // Truncate to hundredths so that 59.999s does not show up as 60.00s.
+        format!("{secs}.{:02}s", d.subsec_millis() / 10)
+    } else if secs < 60 * 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {}m", secs / (60 * 60), secs % (60 * 60) / 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sub_millisecond() {
+        assert_eq!("0µs", format_duration(Duration::ZERO));
+        assert_eq!("999µs", format_duration(Duration::from_nanos(999_999)));
+    }
+
+    #[test]
+    fn milliseconds() {
+        assert_eq!("1ms", format_duration(Duration::from_millis(1)));
+        assert_eq!("450ms", format_duration(Duration::from_micros(450_900)));
+    }
+
+    #[test]
+    fn seconds() {
+        assert_eq!("1.00s", format_duration(Duration::from_secs(1)));
+        assert_eq!("1.23s", format_duration(Duration::from_micros(1_234_567)));
+        assert_eq!("59.99s", format_duration(Duration::from_millis(59_999)));
+    }
+
+    #[test]
+    fn minutes_and_hours() {
+        assert_eq!("1m 0s", format_duration(Duration::from_secs(60)));
+        assert_eq!("3m 2s", format_duration(Duration::from_secs(182)));
+        assert_eq!("59m 59s", format_duration(Duration::from_secs(3599)));
+        assert_eq!("1h 5m", format_duration(Duration::from_secs(3900)));
+    }
+}
@@ -0,0 +1,328 @@
+use crate::*;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// Identifies the variant of a `State` without holding on to any of its data. Kinds are ordered
+/// like their declarations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StateKind {
+    Uninitialized,
+    Stored,
+    Ready,
+    Paused,
+    Error,
+}
+
+impl StateKind {
+    pub const ALL: [StateKind; 5] = [
+        StateKind::Uninitialized,
+        StateKind::Stored,
+        StateKind::Ready,
+        StateKind::Paused,
+        StateKind::Error,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            StateKind::Uninitialized => "uninitialized",
+            StateKind::Stored => "stored",
+            StateKind::Ready => "ready",
+            StateKind::Paused => "paused",
+            StateKind::Error => "error",
+        }
+    }
+
+    /// The number of kinds of states, which is the number of rows of `TRANSITIONS`.
+    pub const COUNT: usize = StateKind::ALL.len();
+
+    /// The kind of state that each operation leads to from each kind of state, indexed by
+    /// `[kind as usize][op as usize]`, or `None` where the operation is not allowed. This is the
+    /// topology of `crate::TRANSITIONS`, derived from it at compile time, as a table that can be
+    /// indexed without comparing names. Transitions that are not an `Operation`, like
+    /// `initialize`, are left out. Resetting is allowed from every state and always leads to the
+    /// stored state.
+    pub const TRANSITIONS: [[Option<StateKind>; Operation::COUNT]; StateKind::COUNT] = {
+        let mut table = [[None; Operation::COUNT]; StateKind::COUNT];
+        let mut kind = 0;
+        while kind < StateKind::COUNT {
+            table[kind][Operation::Reset as usize] = Some(StateKind::Stored);
+            kind += 1;
+        }
+        let mut index = 0;
+        while index < crate::TRANSITIONS.len() {
+            let (from, operation, to) = crate::TRANSITIONS[index];
+            if let (Some(from), Some(op), Some(to)) = (
+                StateKind::from_name(from),
+                Operation::from_name(operation),
+                StateKind::from_name(to),
+            ) {
+                table[from as usize][op as usize] = Some(to);
+            }
+            index += 1;
+        }
+        table
+    };
+
+    /// The names of the transitions out of this kind of state in the order in which they are
+    /// declared in `crate::TRANSITIONS`. Unlike `transition`, this includes transitions that are
+    /// not an `Operation`, like `initialize`, and leaves out resetting.
+    pub fn operations(self) -> &'static [&'static str] {
+        let (names, len) = &OPERATIONS[self as usize];
+        &names[..*len]
+    }
+
+    /// The inverse of `name`. Unknown names have no kind.
+    pub const fn from_name(name: &str) -> Option<StateKind> {
+        let mut index = 0;
+        while index < StateKind::COUNT {
+            let kind = StateKind::ALL[index];
+            if names_equal(kind.name(), name) {
+                return Some(kind);
+            }
+            index += 1;
+        }
+        None
+    }
+
+    /// The kind of state that `op` leads to from this kind of state according to
+    /// `StateKind::TRANSITIONS`, or `None` if `op` is not allowed. This does not take guards like
+    /// `max_ready_count` into account, which makes it useful for validating scripts of operations
+    /// before running them.
+    pub const fn transition(self, op: Operation) -> Option<StateKind> {
+        StateKind::TRANSITIONS[self as usize][op as usize]
+    }
+
+    /// Every kind of state that can be reached from this one through any sequence of operations
+    /// according to `StateKind::TRANSITIONS`, including this one itself. A kind that only reaches
+    /// itself is a dead end.
+    pub fn reachable(self) -> BTreeSet<StateKind> {
+        let mut reachable = BTreeSet::from([self]);
+        let mut pending = vec![self];
+        while let Some(kind) = pending.pop() {
+            for op in Operation::ALL {
+                if let Some(to) = kind.transition(op) {
+                    if reachable.insert(to) {
+                        pending.push(to);
+                    }
+                }
+            }
+        }
+        reachable
+    }
+}
+
+/// The backing storage of `StateKind::operations`, derived from `crate::TRANSITIONS` at compile
+/// time. Every row holds the names of the transitions out of a kind of state followed by unused
+/// slots, together with the number of names.
+static OPERATIONS: [([&str; TRANSITIONS.len()], usize); StateKind::COUNT] = {
+    let mut operations = [([""; TRANSITIONS.len()], 0); StateKind::COUNT];
+    let mut index = 0;
+    while index < TRANSITIONS.len() {
+        let (from, operation, _) = TRANSITIONS[index];
+        if let Some(from) = StateKind::from_name(from) {
+            let (names, len) = &mut operations[from as usize];
+            names[*len] = operation;
+            *len += 1;
+        }
+        index += 1;
+    }
+    operations
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseStateKindError(String);
+
+impl fmt::Display for ParseStateKindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown state {:?}, try one of: {}",
+            self.0,
+            STATES.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for ParseStateKindError {}
+
+/// Parses the name of a state as returned by `StateKind::name`. Unlike parsing an `Operation`, this
+/// is exact so that it round-trips with serialized names.
+impl FromStr for StateKind {
+    type Err = ParseStateKindError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        StateKind::from_name(s).ok_or_else(|| ParseStateKindError(s.to_string()))
+    }
+}
+
+impl State {
+    pub fn kind(&self) -> StateKind {
+        match self {
+            State::Uninitialized(_) => StateKind::Uninitialized,
+            State::Stored(_) => StateKind::Stored,
+            State::Ready(_) => StateKind::Ready,
+            State::Paused(_) => StateKind::Paused,
+            State::Error(_) => StateKind::Error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn compare_kinds() {
+        let state = State::stored_with(0);
+        assert_eq!(StateKind::Stored, state.kind());
+        let state = state.ready().unwrap();
+        assert_eq!(StateKind::Ready, state.kind());
+        assert_ne!(StateKind::Stored, state.kind());
+        assert_eq!(state.name(), state.kind().name());
+    }
+
+    #[test]
+    fn from_name_round_trips() {
+        for kind in StateKind::ALL {
+            assert_eq!(Some(kind), StateKind::from_name(kind.name()));
+            assert_eq!(Ok(kind), kind.name().parse());
+        }
+        assert_eq!(None, StateKind::from_name("bogus"));
+        let error = "bogus"
+            .parse::<StateKind>()
+            .expect_err("bogus is not a state");
+        assert_eq!(
+            "unknown state \"bogus\", try one of: uninitialized, stored, ready, paused, error",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn transition_table_matches_topology() {
+        for (index, kind) in StateKind::ALL.into_iter().enumerate() {
+            assert_eq!(index, kind as usize);
+        }
+        for (index, op) in Operation::ALL.into_iter().enumerate() {
+            assert_eq!(index, op as usize);
+        }
+        for kind in StateKind::ALL {
+            for op in Operation::ALL {
+                let expected = if op == Operation::Reset {
+                    Some(StateKind::Stored)
+                } else {
+                    debug_transitions()
+                        .find(|&(from, operation, _)| from == kind.name() && operation == op.name())
+                        .and_then(|(_, _, to)| StateKind::from_name(to))
+                };
+                assert_eq!(expected, kind.transition(op), "{kind:?} {op:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn operations_follow_topology() {
+        for kind in StateKind::ALL {
+            let expected: Vec<_> = debug_transitions()
+                .filter(|&(from, _, _)| from == kind.name())
+                .map(|(_, operation, _)| operation)
+                .collect();
+            assert_eq!(expected, kind.operations(), "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn transition() {
+        assert_eq!(
+            Some(StateKind::Ready),
+            StateKind::Stored.transition(Operation::Ready)
+        );
+        assert_eq!(None, StateKind::Stored.transition(Operation::Store));
+        assert_eq!(
+            Some(StateKind::Stored),
+            StateKind::Ready.transition(Operation::Store)
+        );
+        assert_eq!(
+            Some(StateKind::Stored),
+            StateKind::Paused.transition(Operation::Reset)
+        );
+        assert_eq!(
+            Some(StateKind::Ready),
+            StateKind::Ready.transition(Operation::Rearm)
+        );
+    }
+
+    #[test]
+    fn transition_matches_apply() {
+        for op in Operation::ALL {
+            let expected = StateKind::Ready.transition(op);
+            let actual = State::ready_with(1)
+                .apply(op)
+                .ok()
+                .map(|(state, _)| state.kind());
+            assert_eq!(expected, actual, "{op:?}");
+        }
+    }
+
+    #[test]
+    fn count_kinds() {
+        let mut counts = HashMap::<StateKind, u32>::new();
+        let mut state = State::stored_with(0);
+        *counts.entry(state.kind()).or_default() += 1;
+        for _ in 0..3 {
+            state = state.ready().unwrap();
+            *counts.entry(state.kind()).or_default() += 1;
+            state = state.store().unwrap();
+            *counts.entry(state.kind()).or_default() += 1;
+        }
+        assert_eq!(Some(&4), counts.get(&StateKind::Stored));
+        assert_eq!(Some(&3), counts.get(&StateKind::Ready));
+        assert_eq!(None, counts.get(&StateKind::Paused));
+    }
+
+    #[test]
+    fn reachable_kinds() {
+        let reachable = StateKind::Stored.reachable();
+        assert!(reachable.contains(&StateKind::Stored));
+        assert!(reachable.contains(&StateKind::Ready));
+        assert_eq!(
+            BTreeSet::from([StateKind::Stored, StateKind::Ready, StateKind::Paused]),
+            reachable,
+            "initializing and erroring are not operations"
+        );
+        assert_eq!(
+            BTreeSet::from([
+                StateKind::Uninitialized,
+                StateKind::Stored,
+                StateKind::Ready,
+                StateKind::Paused
+            ]),
+            StateKind::Uninitialized.reachable()
+        );
+    }
+
+    #[test]
+    fn kinds_in_sorted_collections() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::BTreeMap;
+        use std::hash::{Hash, Hasher};
+
+        let mut counts = BTreeMap::new();
+        for kind in [StateKind::Error, StateKind::Ready, StateKind::Stored] {
+            counts.insert(kind, kind.name().len());
+        }
+        assert_eq!(
+            vec![StateKind::Stored, StateKind::Ready, StateKind::Error],
+            counts.into_keys().collect::<Vec<_>>()
+        );
+        assert!(StateKind::Stored < StateKind::Ready);
+
+        let hash = |kind: StateKind| {
+            let mut hasher = DefaultHasher::new();
+            kind.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(StateKind::Ready), hash(StateKind::Ready));
+    }
+}
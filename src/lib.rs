@@ -1,74 +1,735 @@
+// Rejected transitions hand back the state in the `Err` variant, which makes it as large as the
+// `Ok` variant. Boxing it would only move the state to the heap for no benefit.
+#![allow(clippy::result_large_err)]
+// Without the `std` feature only the `bare` state machine and its configuration are available.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+mod bare;
+#[cfg(all(test, feature = "std"))]
+mod capture;
+#[cfg(feature = "std")]
+mod clock;
+mod config;
+mod config_error;
+mod dsl;
+mod enter_error;
+#[cfg(feature = "std")]
+mod error;
+#[cfg(feature = "std")]
+mod event;
+#[cfg(feature = "std")]
+mod exit_guard;
+#[cfg(feature = "std")]
+mod format;
+#[cfg(feature = "std")]
+mod kind;
+#[cfg(feature = "std")]
+mod logger;
+#[cfg(feature = "std")]
+mod machine;
+#[cfg(feature = "std")]
+mod operation;
+mod overflow;
+#[cfg(feature = "std")]
+mod paused;
+#[cfg(feature = "std")]
+mod persist;
+#[cfg(feature = "rand")]
+mod random;
+#[cfg(feature = "std")]
 mod ready;
+#[cfg(feature = "std")]
+mod replay;
+#[cfg(feature = "std")]
+mod run;
+#[cfg(feature = "schema")]
+mod schema;
+#[cfg(feature = "std")]
+mod snapshot;
+#[cfg(feature = "std")]
 mod stored;
+#[cfg(feature = "testing")]
+mod testing;
+#[cfg(feature = "std")]
+mod topology;
+#[cfg(feature = "std")]
+mod transform;
+#[cfg(feature = "std")]
+mod transition_error;
+#[cfg(feature = "std")]
+pub mod typestate;
+#[cfg(feature = "std")]
+mod uninitialized;
+
+#[cfg(feature = "std")]
+use std::fmt;
+#[cfg(feature = "std")]
+use std::time::Duration;
 
+pub use bare::*;
+#[cfg(feature = "std")]
+pub use clock::*;
+pub use config::*;
+pub use config_error::*;
+pub use enter_error::*;
+#[cfg(feature = "std")]
+pub use error::*;
+#[cfg(feature = "std")]
+pub use event::*;
+#[cfg(feature = "std")]
+pub use format::*;
+#[cfg(feature = "std")]
+pub use kind::*;
+#[cfg(feature = "std")]
+pub use logger::*;
+#[cfg(feature = "std")]
+pub use machine::*;
+#[cfg(feature = "std")]
+pub use operation::*;
+pub use overflow::*;
+#[cfg(feature = "std")]
+pub use paused::*;
+#[cfg(feature = "std")]
 pub use ready::*;
+#[cfg(feature = "std")]
+pub use replay::*;
+#[cfg(feature = "std")]
+pub use run::*;
+#[cfg(feature = "std")]
+pub use snapshot::*;
+#[cfg(feature = "std")]
 pub use stored::*;
+#[cfg(feature = "std")]
+pub use topology::*;
+#[cfg(feature = "std")]
+pub use transform::*;
+#[cfg(feature = "std")]
+pub use transition_error::*;
+#[cfg(feature = "std")]
+pub use uninitialized::*;
 
+#[cfg(feature = "std")]
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+// The serialized variant names match the ones returned by `State::name`.
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum State {
+    Uninitialized(UninitializedState),
     Stored(StoredState),
     Ready(ReadyState),
+    Paused(PausedState),
+    Error(ErrorState),
 }
 
 // Consider definig a constructor (with or without arguments, whatever you need) only for the
 // initial state.
+#[cfg(feature = "std")]
 impl Default for State {
+    /// Starts in the uninitialized state, which has to be initialized with a `Config` before it can
+    /// be used.
     fn default() -> Self {
-        Self::Stored(StoredState::enter(StoredStateInputs { ready_count: 0 }))
+        Self::Uninitialized(UninitializedState::enter(UninitializedStateInputs::new()))
     }
 }
 
+#[cfg(feature = "std")]
 impl State {
+    /// Starts in the stored state with a ready count of 0 and the given configuration, skipping the
+    /// uninitialized state. Panics if the configuration is invalid, see `try_default_with`.
+    pub fn default_with(config: Config) -> Self {
+        Self::try_default_with(config).expect("the configuration should be valid")
+    }
+
+    /// Like `default_with`, but rejects an invalid configuration instead of panicking.
+    pub fn try_default_with(config: Config) -> Result<Self, EnterError> {
+        StoredState::try_enter(StoredStateInputs {
+            config,
+            ..StoredStateInputs::new(0)
+        })
+        .map(Self::Stored)
+    }
+
+    /// Starts in the stored state with the given ready count.
+    pub fn stored_with(ready_count: u64) -> Self {
+        Self::Stored(StoredState::enter(StoredStateInputs::new(ready_count)))
+    }
+
+    /// Starts in the ready state with exactly the given ready count. This does not count as
+    /// entering the ready state so the ready count is not incremented.
+    pub fn ready_with(ready_count: u64) -> Self {
+        Self::Ready(ReadyState::restore(ready_count))
+    }
+
+    /// Borrows the stored state, or returns `None` if the state machine is in another state.
+    pub fn as_stored(&self) -> Option<&StoredState> {
+        match self {
+            State::Stored(state) => Some(state),
+            _ => None,
+        }
+    }
+
+    /// Borrows the ready state, or returns `None` if the state machine is in another state.
+    pub fn as_ready(&self) -> Option<&ReadyState> {
+        match self {
+            State::Ready(state) => Some(state),
+            _ => None,
+        }
+    }
+
     // You could create some representation of the state here which you can share or display.
     pub fn name(&self) -> &'static str {
+        self.kind().name()
+    }
+
+    pub fn ready_count(&self) -> u64 {
         match self {
-            State::Stored(_) => "stored",
-            State::Ready(_) => "ready",
+            State::Uninitialized(_) => 0,
+            State::Stored(state) => state.ready_count(),
+            State::Ready(state) => state.ready_count(),
+            State::Paused(state) => state.ready_count(),
+            State::Error(state) => state.ready_count(),
         }
     }
 
-    pub fn ready(self) -> Result<Self, Self> {
+    /// The number of transitions that have been made before entering the current state.
+    pub fn transition_count(&self) -> u64 {
         match self {
-            State::Stored(state) => Ok(state.ready().into()),
-            _ => Err(self),
+            State::Uninitialized(_) => 0,
+            State::Stored(state) => state.transition_count(),
+            State::Ready(state) => state.transition_count(),
+            State::Paused(state) => state.transition_count(),
+            State::Error(state) => state.transition_count(),
+        }
+    }
+
+    /// Whether the ready count has ever overflowed while entering the ready state, so it was
+    /// clamped at `u64::MAX` in `OverflowMode::Saturate` or wrapped around in `OverflowMode::Wrap`.
+    /// Once set, this stays set through all later transitions.
+    pub fn has_overflowed(&self) -> bool {
+        match self {
+            State::Uninitialized(_) => false,
+            State::Stored(state) => state.overflowed(),
+            State::Ready(state) => state.overflowed(),
+            State::Paused(state) => state.overflowed(),
+            State::Error(state) => state.overflowed(),
+        }
+    }
+
+    /// The name of the state the state machine transitioned from to reach the current state. Useful
+    /// for spotting oscillation between states.
+    pub fn previous(&self) -> Option<&'static str> {
+        match self {
+            State::Uninitialized(_) => None,
+            State::Stored(state) => state.previous(),
+            State::Ready(state) => state.previous(),
+            State::Paused(state) => state.previous(),
+            State::Error(state) => state.previous(),
+        }
+    }
+
+    /// How long the state machine spent in the previous state, or zero if there is none.
+    pub fn time_in_previous(&self) -> Duration {
+        match self {
+            State::Uninitialized(_) => Duration::ZERO,
+            State::Stored(state) => state.time_in_previous(),
+            State::Ready(state) => state.time_in_previous(),
+            State::Paused(state) => state.time_in_previous(),
+            State::Error(state) => state.time_in_previous(),
+        }
+    }
+
+    /// The id of the last request that transitioned the state machine through `apply_request`.
+    pub fn last_request_id(&self) -> Option<u64> {
+        match self {
+            State::Uninitialized(_) => None,
+            State::Stored(state) => state.last_request_id(),
+            State::Ready(state) => state.last_request_id(),
+            State::Paused(state) => state.last_request_id(),
+            State::Error(state) => state.last_request_id(),
+        }
+    }
+
+    fn set_last_request_id(&mut self, last_request_id: Option<u64>) {
+        match self {
+            State::Uninitialized(_) => {}
+            State::Stored(state) => state.set_last_request_id(last_request_id),
+            State::Ready(state) => state.set_last_request_id(last_request_id),
+            State::Paused(state) => state.set_last_request_id(last_request_id),
+            State::Error(state) => state.set_last_request_id(last_request_id),
+        }
+    }
+
+    /// The configuration, which is carried forward through all transitions.
+    pub fn config(&self) -> &Config {
+        match self {
+            State::Uninitialized(_) => &Config::DEFAULT,
+            State::Stored(state) => state.config(),
+            State::Ready(state) => state.config(),
+            State::Paused(state) => state.config(),
+            State::Error(state) => state.config(),
+        }
+    }
+
+    /// The clock used to measure the time spent in the current state.
+    pub fn clock(&self) -> &std::sync::Arc<dyn Clock> {
+        match self {
+            State::Uninitialized(state) => state.clock(),
+            State::Stored(state) => state.clock(),
+            State::Ready(state) => state.clock(),
+            State::Paused(state) => state.clock(),
+            State::Error(state) => state.clock(),
+        }
+    }
+
+    /// The tracing span that is open while the state machine is in the current state.
+    pub fn span(&self) -> &tracing::Span {
+        match self {
+            State::Uninitialized(state) => state.span(),
+            State::Stored(state) => state.span(),
+            State::Ready(state) => state.span(),
+            State::Paused(state) => state.span(),
+            State::Error(state) => state.span(),
+        }
+    }
+
+    /// When the current state was entered, for example to correlate it with external traces.
+    pub fn entered_at(&self) -> Timestamp {
+        match self {
+            State::Uninitialized(state) => state.entered_at(),
+            State::Stored(state) => state.entered_at(),
+            State::Ready(state) => state.entered_at(),
+            State::Paused(state) => state.entered_at(),
+            State::Error(state) => state.entered_at(),
+        }
+    }
+
+    /// How long the state machine has been in the current state. Observing this does not cause a
+    /// transition.
+    pub fn elapsed(&self) -> Duration {
+        match self {
+            State::Uninitialized(state) => state.elapsed(),
+            State::Stored(state) => state.elapsed(),
+            State::Ready(state) => state.elapsed(),
+            State::Paused(state) => state.elapsed(),
+            State::Error(state) => state.elapsed(),
+        }
+    }
+
+    /// The operations that can be performed from the current state. These are derived from the
+    /// transitions listed in `TRANSITIONS`, except that an unrecoverable error can not be
+    /// recovered from. Entering the error state and resetting are always possible and not listed.
+    ///
+    /// The operations are listed in the order in which their transitions are declared in
+    /// `TRANSITIONS`, which is their priority when more than one is legal. Callers that pick one
+    /// of them, like random stepping, can rely on this order to be reproducible.
+    pub fn available_operations(&self) -> &'static [&'static str] {
+        match self {
+            State::Error(state) if !state.is_recoverable() => &[],
+            _ => self.kind().operations(),
+        }
+    }
+
+    /// Hands back the unchanged state together with a description of why the operation was
+    /// rejected. Rejections are logged as warnings so they show up regardless of the caller.
+    fn reject(self, operation: &'static str) -> Result<Self, (Self, TransitionError)> {
+        let error = TransitionError::Illegal {
+            operation,
+            state: self.name(),
+            valid_operations: self.available_operations(),
+        };
+        tracing::warn!(
+            operation,
+            state = self.name(),
+            "Rejected {operation} from the {} state.",
+            self.name()
+        );
+        Err((self, error))
+    }
+
+    /// Configures an uninitialized state machine, after which it is in the stored state.
+    pub fn initialize(self, config: Config) -> Result<Self, (Self, TransitionError)> {
+        match self {
+            State::Uninitialized(state) => match state.initialize(config) {
+                Ok(state) => Ok(State::Stored(state)),
+                Err((state, error)) => {
+                    tracing::warn!(%error, "Rejected initialize with an invalid configuration.");
+                    let error = TransitionError::InvalidConfig {
+                        operation: "initialize",
+                        state: "uninitialized",
+                        error,
+                    };
+                    Err((State::Uninitialized(state), error))
+                }
+            },
+            _ => self.reject("initialize"),
         }
     }
 
-    pub fn store(self) -> Result<Self, Self> {
+    pub fn ready(self) -> Result<Self, (Self, TransitionError)> {
+        match self {
+            State::Stored(state) => match state.ready_checked() {
+                Ok(result) => Ok(result.into()),
+                Err(state) => State::Stored(state).reject("ready"),
+            },
+            _ => self.reject("ready"),
+        }
+    }
+
+    pub fn store(self) -> Result<Self, (Self, TransitionError)> {
         match self {
             State::Ready(state) => Ok(state.store().into()),
+            State::Paused(state) => Ok(state.store().into()),
+            _ => self.reject("store"),
+        }
+    }
+
+    /// Re-enters the ready state from the ready state, see `ReadyState::rearm`. Like `ready`, this
+    /// is rejected when it would exceed the configured `max_ready_count`.
+    pub fn rearm(self) -> Result<Self, (Self, TransitionError)> {
+        match self {
+            State::Ready(state)
+                if state
+                    .config()
+                    .next_ready_count(state.ready_count())
+                    .is_some() =>
+            {
+                Ok(state.rearm().into())
+            }
+            _ => self.reject("rearm"),
+        }
+    }
+
+    pub fn pause(self) -> Result<Self, (Self, TransitionError)> {
+        match self {
+            State::Ready(state) => Ok(state.pause().into()),
+            _ => self.reject("pause"),
+        }
+    }
+
+    pub fn resume(self) -> Result<Self, (Self, TransitionError)> {
+        match self {
+            State::Paused(state) => match state.resume() {
+                Ok(result) => Ok(result.into()),
+                Err(state) => State::Paused(state).reject("resume"),
+            },
+            _ => self.reject("resume"),
+        }
+    }
+
+    pub fn recover(self) -> Result<Self, (Self, TransitionError)> {
+        match self {
+            State::Error(state) => match state.recover() {
+                Ok(result) => Ok(result.into()),
+                Err(state) => State::Error(state).reject("recover"),
+            },
+            _ => self.reject("recover"),
+        }
+    }
+
+    /// Enters a recoverable error state. This is possible from any state, which is why it can not
+    /// fail. The ready count is carried forward so it survives recovering from the error.
+    pub fn error(self, reason: String) -> Self {
+        self.error_with(reason, true)
+    }
+
+    /// Like `State::error`, but `recoverable` decides whether the error can be recovered from. An
+    /// unrecoverable error can only be left by resetting.
+    pub fn error_with(self, reason: String, recoverable: bool) -> Self {
+        State::Error(match self {
+            State::Uninitialized(state) => state.error(reason, recoverable),
+            State::Stored(state) => state.error(reason, recoverable),
+            State::Ready(state) => state.error(reason, recoverable),
+            State::Paused(state) => state.error(reason, recoverable),
+            State::Error(state) => state.error(reason, recoverable),
+        })
+    }
+
+    /// Stores a ready state that has been idle for at least its `auto_store_after` duration. Any
+    /// other state is handed back unchanged.
+    pub fn poll_timeout(self) -> Self {
+        match self {
+            State::Ready(state) => state.poll_timeout().into(),
+            state => state,
+        }
+    }
+
+    /// How long until `poll_timeout` will store the current state, or `None` if it never will.
+    pub fn time_until_auto_store(&self) -> Option<Duration> {
+        match self {
+            State::Ready(state) => state
+                .config()
+                .auto_store_after
+                .map(|after| after.saturating_sub(state.elapsed())),
+            _ => None,
+        }
+    }
+
+    /// Aborts whatever the state machine was doing and starts over from the stored state with a
+    /// ready count of 0, keeping the configuration. The current state is exited normally so its
+    /// `exit` code still runs.
+    pub fn reset(self) -> Self {
+        let from = self.name();
+        let (config, last_request_id, time_in_state) = match self {
+            State::Uninitialized(state) => (Config::default(), None, state.exit().time_in_state),
+            State::Stored(state) => {
+                let outputs = state.exit();
+                (
+                    outputs.config,
+                    outputs.last_request_id,
+                    outputs.time_in_state,
+                )
+            }
+            State::Ready(state) => {
+                let outputs = state.exit();
+                (
+                    outputs.config,
+                    outputs.last_request_id,
+                    outputs.time_in_state,
+                )
+            }
+            State::Paused(state) => {
+                let outputs = state.exit();
+                (
+                    outputs.config,
+                    outputs.last_request_id,
+                    outputs.time_in_state,
+                )
+            }
+            State::Error(state) => {
+                let outputs = state.exit();
+                (
+                    outputs.config,
+                    outputs.last_request_id,
+                    outputs.time_in_state,
+                )
+            }
+        };
+        Self::Stored(StoredState::enter(StoredStateInputs {
+            config,
+            last_request_id,
+            from: Some(from),
+            time_in_previous: time_in_state,
+            ..StoredStateInputs::new(0)
+        }))
+    }
+
+    /// Leaves the current state without entering another one, for example when shutting down, so
+    /// that its `exit` code still runs. Returns how long the state was occupied.
+    pub fn exit(self) -> Duration {
+        match self {
+            State::Uninitialized(state) => state.exit().time_in_state,
+            State::Stored(state) => state.exit().time_in_state,
+            State::Ready(state) => state.exit().time_in_state,
+            State::Paused(state) => state.exit().time_in_state,
+            State::Error(state) => state.exit().time_in_state,
+        }
+    }
+
+    /// Makes sure the state is ready. A ready state is returned as is, so calling this twice does
+    /// not increment the ready count twice. The stored and paused states transition to ready
+    /// normally, every other state, as well as a stored or paused state at its `max_ready_count`,
+    /// is handed back in the `Err` variant.
+    pub fn ensure_ready(self) -> Result<Self, Self> {
+        match self {
+            State::Ready(_) => Ok(self),
+            State::Stored(_) => self.ready().map_err(|(state, _)| state),
+            State::Paused(_) => self.resume().map_err(|(state, _)| state),
             _ => Err(self),
         }
     }
+
+    /// Ends up in the stored state no matter what the current state is, preserving the ready
+    /// count. A stored state is returned as is, without running its `exit` code. The ready and
+    /// paused states are stored normally, an uninitialized state is initialized with the default
+    /// configuration and an error state is left even if it is not recoverable.
+    pub fn force_store(self) -> Self {
+        match self {
+            State::Uninitialized(state) => Self::Stored(
+                state
+                    .initialize(Config::default())
+                    .expect("the default configuration should be valid"),
+            ),
+            State::Stored(_) => self,
+            State::Ready(state) => state.store().into(),
+            State::Paused(state) => state.store().into(),
+            State::Error(state) => {
+                let ErrorStateOutputs {
+                    ready_count,
+                    transition_count,
+                    overflowed,
+                    clock,
+                    logger,
+                    transform,
+                    config,
+                    last_request_id,
+                    time_in_state,
+                } = state.exit();
+                Self::Stored(StoredState::enter(StoredStateInputs {
+                    ready_count,
+                    transition_count,
+                    overflowed,
+                    clock,
+                    logger,
+                    transform,
+                    config,
+                    last_request_id,
+                    from: Some("error"),
+                    time_in_previous: time_in_state,
+                    now: None,
+                }))
+            }
+        }
+    }
+}
+
+/// Formats the state as a status line, for example `ready (count=3, age=1.20s)`. Use `State::name`
+/// to obtain just the name of the state.
+#[cfg(feature = "std")]
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (count={}, age={})",
+            self.name(),
+            self.ready_count(),
+            format_duration(self.elapsed())
+        )
+    }
+}
+
+// Lifts a bare state into the enum. These do not overlap with the impls generated by
+// `impl_state_transition_result!`, which convert from the transition results instead.
+#[cfg(feature = "std")]
+impl From<UninitializedState> for State {
+    fn from(state: UninitializedState) -> Self {
+        Self::Uninitialized(state)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<StoredState> for State {
+    fn from(state: StoredState) -> Self {
+        Self::Stored(state)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ReadyState> for State {
+    fn from(state: ReadyState) -> Self {
+        Self::Ready(state)
+    }
 }
 
-/// Provides From<$TransitionResult> for State.
+#[cfg(feature = "std")]
+impl From<PausedState> for State {
+    fn from(state: PausedState) -> Self {
+        Self::Paused(state)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ErrorState> for State {
+    fn from(state: ErrorState) -> Self {
+        Self::Error(state)
+    }
+}
+
+/// Provides From<$TransitionResult> for State and TryFrom<State> for $TransitionResult. The latter
+/// hands back the original state if it is not one of the listed variants.
+///
+/// Each variant can optionally be followed by a guard, for example
+/// `Ready(ReadyState) if |state| state.ready_count() < 100`. The generated `try_into_state`
+/// method evaluates the guard and hands back the transition result if it does not hold. Variants
+/// without a guard always convert.
+///
+/// Writing `pub enum $TransitionResult for $Machine { .. }` converts into another enum than
+/// `State`, which `state_machine!` relies on.
 #[macro_export]
 macro_rules! impl_state_transition_result {
-    (pub enum $TransitionResult: ident { $($Variant: ident ($State: ty)),* $(,)? }) => {
+    (pub enum $TransitionResult: ident {
+        $($Variant: ident ($State: ty) $(if $guard: expr)?),* $(,)?
+    }) => {
+        $crate::impl_state_transition_result! {
+            pub enum $TransitionResult for State {
+                $($Variant($State) $(if $guard)?),*
+            }
+        }
+    };
+    (pub enum $TransitionResult: ident for $Machine: ident {
+        $($Variant: ident ($State: ty) $(if $guard: expr)?),* $(,)?
+    }) => {
         #[derive(Debug)]
         pub enum $TransitionResult {
             $($Variant($State)),*
         }
 
-        impl From<$TransitionResult> for State {
+        impl From<$TransitionResult> for $Machine {
             fn from(value: $TransitionResult) -> Self {
                 match value {
                     $($TransitionResult::$Variant(state) => Self::$Variant(state)),*
                 }
             }
         }
-    }
+
+        impl TryFrom<$Machine> for $TransitionResult {
+            type Error = $Machine;
+
+            fn try_from(value: $Machine) -> Result<Self, Self::Error> {
+                match value {
+                    $($Machine::$Variant(state) => Ok(Self::$Variant(state)),)*
+                    #[allow(unreachable_patterns)]
+                    other => Err(other),
+                }
+            }
+        }
+
+        impl $TransitionResult {
+            pub fn try_into_state(self) -> Result<$Machine, Self> {
+                #[allow(dead_code)]
+                fn check<S>(guard: impl FnOnce(&S) -> bool, state: &S) -> bool {
+                    guard(state)
+                }
+
+                match self {
+                    $($TransitionResult::$Variant(state) => {
+                        $(if !check($guard, &state) {
+                            return Err(Self::$Variant(state));
+                        })?
+                        Ok($Machine::$Variant(state))
+                    }),*
+                }
+            }
+        }
+    };
 }
 
-#[cfg(test)]
+/// Provides From<$Narrow> for $Wide, where both are transition results declared with
+/// `impl_state_transition_result!` and the listed variants of $Narrow also exist in $Wide. This
+/// lifts the result of a transition into a broader result, for example when composing
+/// hierarchical state machines. All variants of $Narrow have to be listed, otherwise the
+/// generated match is not exhaustive and fails to compile.
+#[macro_export]
+macro_rules! impl_transition_result_widening {
+    ($Narrow: ident => $Wide: ident { $($Variant: ident),* $(,)? }) => {
+        impl From<$Narrow> for $Wide {
+            fn from(value: $Narrow) -> Self {
+                match value {
+                    $($Narrow::$Variant(state) => Self::$Variant(state)),*
+                }
+            }
+        }
+    };
+}
+
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
 
     #[test]
     fn test() {
-        let state = State::Stored(StoredState::enter(StoredStateInputs { ready_count: 0 }));
-        let state = state
+        let state = State::Stored(StoredState::enter(StoredStateInputs::new(0)));
+        let (state, _) = state
             .store()
             .expect_err("can not transition from stored to stored");
         let state = state
@@ -84,4 +745,450 @@ mod tests {
             .expect("should be able to transition from ready to stored");
         _ = state;
     }
+
+    /// Constructs a state for every name in `STATES`.
+    fn state_named(name: &str) -> State {
+        match name {
+            "uninitialized" => State::default(),
+            "stored" => State::stored_with(0),
+            "ready" => State::stored_with(0).ready().unwrap(),
+            "paused" => state_named("ready").pause().unwrap(),
+            "error" => State::stored_with(0).error("test".to_string()),
+            _ => panic!("unknown state {name}"),
+        }
+    }
+
+    #[test]
+    fn transitions_match_transition_methods() {
+        for from in STATES {
+            // Resetting is possible from every state and is not listed in the table.
+            for op in Operation::ALL
+                .into_iter()
+                .filter(|&op| op != Operation::Reset)
+            {
+                let operation = op.name();
+                let expected = debug_transitions()
+                    .find(|&(f, o, _)| f == *from && o == operation)
+                    .map(|(_, _, to)| to);
+                let actual = state_named(from)
+                    .apply(op)
+                    .map(|(state, _)| state.name())
+                    .map_err(|(state, _)| state.name());
+                match expected {
+                    Some(to) => assert_eq!(Ok(to), actual, "{from} -> {operation}"),
+                    None => assert_eq!(Err(*from), actual, "{from} -> {operation}"),
+                }
+            }
+
+            let listed: Vec<_> = debug_transitions()
+                .filter(|&(f, _, _)| f == *from)
+                .map(|(_, operation, _)| operation)
+                .collect();
+            assert_eq!(listed, state_named(from).available_operations(), "{from}");
+        }
+    }
+
+    #[test]
+    fn available_operations_follow_declaration_order() {
+        for from in STATES {
+            let state = state_named(from);
+            let declared: Vec<_> = TRANSITIONS
+                .iter()
+                .filter(|&&(f, _, _)| f == *from)
+                .map(|&(_, operation, _)| operation)
+                .collect();
+            assert_eq!(declared, state.available_operations(), "{from}");
+            assert_eq!(
+                state.available_operations(),
+                state.available_operations(),
+                "{from}"
+            );
+        }
+    }
+
+    #[test]
+    fn available_operations_per_state() {
+        assert_eq!(
+            ["initialize"],
+            state_named("uninitialized").available_operations()
+        );
+        assert_eq!(["ready"], state_named("stored").available_operations());
+        assert_eq!(
+            ["store", "pause", "rearm"],
+            state_named("ready").available_operations()
+        );
+        assert_eq!(
+            ["resume", "store"],
+            state_named("paused").available_operations()
+        );
+        assert_eq!(["recover"], state_named("error").available_operations());
+        assert!(State::stored_with(0)
+            .error_with("unrecoverable".to_string(), false)
+            .available_operations()
+            .is_empty());
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let state = State::stored_with(0)
+            .ready()
+            .expect("should be able to transition from stored to ready")
+            .pause()
+            .expect("should be able to transition from ready to paused");
+        assert_eq!("paused", state.name());
+        let (state, _) = state
+            .ready()
+            .expect_err("can not transition from paused to ready");
+        let state = state
+            .resume()
+            .expect("should be able to transition from paused to ready");
+        assert_eq!("ready", state.name());
+        let state = state
+            .pause()
+            .expect("should be able to transition from ready to paused")
+            .store()
+            .expect("should be able to transition from paused to stored");
+        assert_eq!("stored", state.name());
+    }
+
+    #[test]
+    fn transition_result_conversions_with_single_variant() {
+        let state: State = StoredState::enter(StoredStateInputs::new(0)).ready().into();
+        assert_eq!("ready", state.name());
+        let result = StoredStateTransitionResult::try_from(state)
+            .expect("a ready state should convert into the transition result");
+        let StoredStateTransitionResult::Ready(state) = result;
+        let state = StoredStateTransitionResult::try_from(State::from(state.store()))
+            .expect_err("a stored state should not convert into the transition result");
+        assert_eq!("stored", state.name());
+    }
+
+    #[test]
+    fn transition_result_conversions_with_multiple_variants() {
+        impl_state_transition_result! {
+            pub enum StoredOrReady {
+                Stored(StoredState),
+                Ready(ReadyState),
+            }
+        }
+
+        let result = StoredOrReady::try_from(State::stored_with(0))
+            .expect("a stored state should convert into the transition result");
+        assert!(matches!(result, StoredOrReady::Stored(_)));
+        let state = State::from(result).ready().unwrap();
+        let result = StoredOrReady::try_from(state)
+            .expect("a ready state should convert into the transition result");
+        assert!(matches!(result, StoredOrReady::Ready(_)));
+        let state = State::from(result).pause().unwrap();
+        let state = StoredOrReady::try_from(state)
+            .expect_err("a paused state should not convert into the transition result");
+        assert_eq!("paused", state.name());
+    }
+
+    #[test]
+    fn widen_transition_result() {
+        impl_state_transition_result! {
+            pub enum StoredOrReady {
+                Stored(StoredState),
+                Ready(ReadyState),
+            }
+        }
+        impl_transition_result_widening!(StoredStateTransitionResult => StoredOrReady { Ready });
+
+        let result = StoredState::enter(StoredStateInputs::new(0)).ready();
+        let result = StoredOrReady::from(result);
+        let StoredOrReady::Ready(state) = result else {
+            panic!("the ready variant should be preserved");
+        };
+        assert_eq!(1, state.ready_count());
+    }
+
+    #[test]
+    fn start_with_ready_count() {
+        let state = State::ready_with(10);
+        assert_eq!("ready", state.name());
+        assert_eq!(10, state.ready_count());
+        let state = state
+            .store()
+            .expect("should be able to transition from ready to stored");
+        assert_eq!(10, state.ready_count());
+
+        let state = State::stored_with(7);
+        assert_eq!("stored", state.name());
+        assert_eq!(7, state.ready_count());
+    }
+
+    #[test]
+    fn borrow_specific_state() {
+        let state = State::stored_with(0);
+        assert!(state.as_ready().is_none());
+        assert_eq!(Some(0), state.as_stored().map(StoredState::ready_count));
+        let state = state.ready().unwrap();
+        assert!(state.as_stored().is_none());
+        let ready = state.as_ready().expect("state should be ready");
+        assert_eq!(1, ready.ready_count());
+    }
+
+    #[test]
+    fn reset_returns_to_initial_state() {
+        let state = State::Ready(ReadyState::enter(ReadyStateInputs::new(4)));
+        assert_eq!(5, state.ready_count());
+        let state = state.reset();
+        let stored = state.as_stored().expect("state should be stored");
+        assert_eq!(0, stored.ready_count());
+        let state = state.error_with("unrecoverable".to_string(), false).reset();
+        assert_eq!("stored", state.name(), "reset should escape any state");
+    }
+
+    #[test]
+    fn transition_result_without_guard_always_converts() {
+        let result = StoredState::enter(StoredStateInputs::new(1000)).ready();
+        let state = result
+            .try_into_state()
+            .expect("unguarded variants always convert");
+        assert_eq!(1001, state.ready_count());
+    }
+
+    #[test]
+    fn transition_result_with_guard() {
+        impl_state_transition_result! {
+            pub enum GuardedTransitionResult {
+                Stored(StoredState),
+                Ready(ReadyState) if |state| state.ready_count() < 100,
+            }
+        }
+
+        let state = GuardedTransitionResult::Ready(ReadyState::restore(99))
+            .try_into_state()
+            .expect("the guard holds");
+        assert_eq!(StateKind::Ready, state.kind());
+
+        let result = GuardedTransitionResult::Ready(ReadyState::restore(100))
+            .try_into_state()
+            .expect_err("the guard does not hold");
+        assert!(
+            matches!(result, GuardedTransitionResult::Ready(ref state) if state.ready_count() == 100)
+        );
+
+        let state =
+            GuardedTransitionResult::Stored(StoredState::enter(StoredStateInputs::new(100)))
+                .try_into_state()
+                .expect("the stored variant is not guarded");
+        assert_eq!(StateKind::Stored, state.kind());
+    }
+
+    #[test]
+    fn display_includes_count_and_age() {
+        let state = State::stored_with(0)
+            .ready()
+            .expect("should be able to transition from stored to ready");
+        let status = state.to_string();
+        assert!(status.starts_with("ready (count=1, age="), "{status}");
+        assert!(status.ends_with(')'), "{status}");
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn elapsed_increases_without_transitioning() {
+        let state = State::stored_with(0);
+        std::thread::sleep(Duration::from_millis(5));
+        let first = state.elapsed();
+        assert!(first >= Duration::from_millis(5));
+        let second = state.elapsed();
+        assert!(
+            second >= first,
+            "elapsed should be monotonically increasing"
+        );
+        assert_eq!("stored", state.name());
+    }
+
+    #[test]
+    fn error_carries_ready_count_forward() {
+        let state = State::stored_with(0)
+            .ready()
+            .expect("should be able to transition from stored to ready")
+            .error("lost connection".to_string());
+        match state {
+            State::Error(ref state) => {
+                assert_eq!(1, state.ready_count());
+                assert_eq!("lost connection", state.reason());
+            }
+            _ => panic!("state should be error"),
+        };
+        let (state, _) = state
+            .ready()
+            .expect_err("can not transition from error to ready");
+        let state = state
+            .recover()
+            .expect("should be able to recover from a recoverable error");
+        let stored = state.as_stored().expect("state should be stored");
+        assert_eq!(1, stored.ready_count());
+    }
+
+    #[test]
+    fn unrecoverable_error_has_no_valid_operations() {
+        let state = State::stored_with(0).error_with("corrupted".to_string(), false);
+        let (state, error) = state
+            .recover()
+            .expect_err("can not recover from an unrecoverable error");
+        assert_eq!("error", state.name());
+        assert!(matches!(
+            error,
+            TransitionError::Illegal {
+                valid_operations: &[],
+                ..
+            }
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        // Entering the ready state increments the ready count.
+        let state = State::Ready(ReadyState::enter(ReadyStateInputs::new(2)));
+        let json = serde_json::to_string(&state).expect("state should serialize");
+        assert_eq!(
+            concat!(
+                r#"{"ready":{"ready_count":3,"transition_count":0,"overflowed":false,"config":{"#,
+                r#""max_ready_count":null,"auto_store_after":null,"#,
+                r#""overflow_mode":"Saturate","step":1}}}"#
+            ),
+            json
+        );
+        let state: State = serde_json::from_str(&json).expect("state should deserialize");
+        match state {
+            State::Ready(ref state) => assert_eq!(3, state.ready_count()),
+            _ => panic!("state should be ready"),
+        };
+    }
+
+    #[cfg(not(feature = "timing"))]
+    #[test]
+    fn transitions_without_timing() {
+        assert_eq!(0, std::mem::size_of::<Timestamp>());
+        let state = State::stored_with(0)
+            .ready()
+            .and_then(State::pause)
+            .and_then(State::resume)
+            .expect("should be able to transition from stored to ready to paused to ready");
+        assert_eq!("ready", state.name());
+        assert_eq!(2, state.ready_count());
+        assert_eq!(Duration::ZERO, state.elapsed());
+        assert_eq!(Duration::ZERO, state.time_in_previous());
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn entered_at_is_now() {
+        let before = std::time::Instant::now();
+        let state = State::stored_with(0);
+        let entered_at = state.entered_at();
+        assert!(before <= entered_at);
+        assert!(entered_at.elapsed() < Duration::from_secs(1));
+        let state = state.ready().unwrap();
+        assert!(entered_at <= state.entered_at());
+    }
+
+    #[test]
+    fn ensure_ready_is_idempotent() {
+        let state = State::stored_with(0)
+            .ensure_ready()
+            .and_then(State::ensure_ready)
+            .expect("should be able to transition from stored to ready");
+        assert_eq!("ready", state.name());
+        assert_eq!(1, state.ready_count());
+        assert_eq!(1, state.transition_count());
+
+        let state = State::stored_with(0)
+            .error("corrupted".to_string())
+            .ensure_ready()
+            .expect_err("can not transition from error to ready");
+        assert_eq!("error", state.name());
+    }
+
+    #[test]
+    fn force_store() {
+        let state = State::stored_with(2).force_store();
+        assert_eq!("stored", state.name());
+        assert_eq!(2, state.ready_count());
+        assert_eq!(
+            0,
+            state.transition_count(),
+            "the stored state is not re-entered"
+        );
+
+        let state = State::ready_with(3).force_store();
+        assert_eq!("stored", state.name());
+        assert_eq!(3, state.ready_count());
+
+        let state = State::stored_with(4)
+            .error_with("corrupted".to_string(), false)
+            .force_store();
+        assert_eq!("stored", state.name());
+        assert_eq!(4, state.ready_count());
+    }
+
+    #[test]
+    fn from_bare_state() {
+        let state: State = StoredState::enter(StoredStateInputs::new(2)).into();
+        assert_eq!("stored", state.name());
+        assert_eq!(2, state.ready_count());
+
+        let state = State::from(ReadyState::enter(ReadyStateInputs::new(2)));
+        assert_eq!("ready", state.name());
+        assert_eq!(3, state.ready_count());
+    }
+
+    #[test]
+    fn transition_error_lists_valid_operations() {
+        let state = State::stored_with(0);
+        let (state, error) = state
+            .store()
+            .expect_err("can not transition from stored to stored");
+        assert_eq!("stored", state.name(), "the state should be handed back");
+        assert_eq!(
+            TransitionError::Illegal {
+                operation: "store",
+                state: "stored",
+                valid_operations: &["ready"],
+            },
+            error
+        );
+        assert_eq!(
+            "can not store from the stored state, valid operations are: ready",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn transition_error_can_be_bubbled_up() {
+        fn store_twice() -> Result<State, Box<dyn std::error::Error>> {
+            let state = State::stored_with(0)
+                .ready()
+                .map_err(|(_, error)| error)?
+                .store()
+                .map_err(|(_, error)| error)?;
+            let state = state.store().map_err(|(_, error)| error)?;
+            Ok(state)
+        }
+
+        let error = store_twice().expect_err("storing twice should fail");
+        assert_eq!(
+            "can not store from the stored state, valid operations are: ready",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn rejection_logs_warning() {
+        use crate::capture::capture;
+
+        let (_, records) = capture(|| State::stored_with(0).store());
+        let event = records
+            .iter()
+            .find(|record| record.level() == Some(tracing::Level::WARN))
+            .expect("rejecting should log a warning");
+        assert_eq!(Some("\"store\""), event.field("operation"));
+        assert_eq!(Some("\"stored\""), event.field("state"));
+    }
 }
@@ -0,0 +1,119 @@
+use crate::*;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Receives the logs of the states. Swapping out the logger makes it possible to route them
+/// somewhere other than `tracing`, like `log`, stdout or a buffer in tests.
+pub trait StateLogger: fmt::Debug + Send + Sync {
+    /// Called by `enter` with the name of the state that is entered and the ready count it
+    /// starts out with. The span of the state is entered during the call. Does nothing by default.
+    fn state_entered(&self, name: &str, ready_count: u64) {
+        _ = (name, ready_count);
+    }
+
+    /// Called by `exit` with the name of the state that is left, how long it was occupied and the
+    /// ready count at that moment. The span of the state is entered during the call.
+    fn state_exited(&self, name: &str, duration: Duration, ready_count: u64);
+}
+
+/// Logs through `tracing`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TracingLogger;
+
+impl StateLogger for TracingLogger {
+    fn state_entered(&self, name: &str, ready_count: u64) {
+        tracing::event!(
+            tracing::Level::INFO,
+            state = name,
+            ready_count,
+            "Entered {name} state."
+        );
+    }
+
+    fn state_exited(&self, name: &str, duration: Duration, ready_count: u64) {
+        // Without the `timing` feature the duration is always zero, so it is left out.
+        if !cfg!(feature = "timing") {
+            tracing::event!(
+                tracing::Level::INFO,
+                state = name,
+                ready_count,
+                "Left {name} state."
+            );
+            return;
+        }
+
+        // The fields allow layers like OpenTelemetry to record this as a structured event.
+        tracing::event!(
+            tracing::Level::INFO,
+            state = name,
+            duration_ms = duration.as_millis(),
+            ready_count,
+            "Spent {} in {name} state.",
+            format_duration(duration)
+        );
+    }
+}
+
+/// The logger used when none is specified.
+pub fn tracing_logger() -> Arc<dyn StateLogger> {
+    Arc::new(TracingLogger)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct CapturingLogger {
+        exits: Mutex<Vec<(String, u64)>>,
+    }
+
+    impl StateLogger for CapturingLogger {
+        fn state_exited(&self, name: &str, _duration: Duration, ready_count: u64) {
+            self.exits
+                .lock()
+                .unwrap()
+                .push((name.to_string(), ready_count));
+        }
+    }
+
+    #[test]
+    fn logs_every_exit() {
+        let logger = Arc::new(CapturingLogger::default());
+        let state = State::Stored(StoredState::enter(StoredStateInputs {
+            logger: Arc::clone(&logger) as Arc<dyn StateLogger>,
+            ..StoredStateInputs::new(0)
+        }));
+        let state = state
+            .ready()
+            .and_then(State::pause)
+            .and_then(State::store)
+            .expect("should be able to transition from stored to ready to paused to stored");
+
+        let expected = [("stored", 0), ("ready", 1), ("paused", 1)]
+            .map(|(name, ready_count)| (name.to_string(), ready_count));
+        assert_eq!(expected.as_slice(), *logger.exits.lock().unwrap());
+        drop(state);
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn tracing_logger_brackets_states() {
+        use crate::capture::capture;
+
+        let (_, records) = capture(|| {
+            let state = StoredState::enter(StoredStateInputs::new(0));
+            let StoredStateTransitionResult::Ready(state) = state.ready();
+            state.exit()
+        });
+        let messages: Vec<_> = records
+            .iter()
+            .filter_map(|record| record.field("message"))
+            .filter(|message| message.contains("state."))
+            .map(|message| message.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(vec!["Entered", "Spent", "Entered", "Spent"], messages);
+    }
+}
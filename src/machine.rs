@@ -0,0 +1,1090 @@
+use crate::*;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Write};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+/// How many snapshots a subscriber can fall behind before it starts missing them.
+const SNAPSHOT_CAPACITY: usize = 64;
+
+/// Allows attaching side effects like metrics or logging to every transition without modifying the
+/// `enter` and `exit` methods of the states.
+///
+/// Observers can not start another transition on the machine that notifies them. The transition
+/// methods take `&mut self` while observers are only handed `&self` and have to be `'static`, so
+/// the borrow checker rejects nested calls, see `tests/compile_fail`. Sharing the machine through a
+/// `Mutex` makes the nested `try_lock` fail instead. A nested transition that reaches the machine
+/// anyway is rejected with `TransitionError::Reentrant`.
+///
+/// Observers have to be `Send` so that the `StateMachine` can be moved to another task.
+pub trait TransitionObserver: Send {
+    /// Called after a successful transition with the name of the state that was left.
+    fn on_exit(&self, from: &'static str) {
+        _ = from;
+    }
+
+    /// Called after `on_exit` with the name of the state that was entered.
+    fn on_enter(&self, to: &'static str) {
+        _ = to;
+    }
+}
+
+/// Allows vetoing transitions by an external policy without modifying the states. The guard is only
+/// consulted for operations that are legal from the current state.
+pub trait TransitionGuard: Send {
+    /// Whether `op` may be applied to a state of kind `from`.
+    fn allow(&self, from: StateKind, op: Operation) -> bool;
+}
+
+/// Allows at most `max_transitions` transitions within any `window`, see
+/// `StateMachine::set_rate_limit`.
+#[derive(Debug)]
+struct RateLimit {
+    max_transitions: usize,
+    window: Duration,
+    // When the transitions within the last `window` happened, oldest first.
+    recent: VecDeque<Instant>,
+}
+
+impl RateLimit {
+    /// Forgets the transitions that fell out of the window, and returns how long to wait before
+    /// the next transition is allowed if the limit has been reached.
+    fn check(&mut self, now: Instant) -> Option<Duration> {
+        while let Some(&oldest) = self.recent.front() {
+            if now.saturating_duration_since(oldest) < self.window {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        if self.recent.len() < self.max_transitions {
+            return None;
+        }
+        let oldest = self.recent.front().copied().unwrap_or(now);
+        Some(self.window - now.saturating_duration_since(oldest))
+    }
+}
+
+/// The total time spent in each state, accumulated over all visits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Durations {
+    pub uninitialized: Duration,
+    pub stored: Duration,
+    pub ready: Duration,
+    pub paused: Duration,
+    pub error: Duration,
+}
+
+impl Durations {
+    /// Looks up the duration for the state with the given name. Unknown names have no duration.
+    pub fn get(&self, name: &str) -> Duration {
+        match name {
+            "uninitialized" => self.uninitialized,
+            "stored" => self.stored,
+            "ready" => self.ready,
+            "paused" => self.paused,
+            "error" => self.error,
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn add(&mut self, name: &str, duration: Duration) {
+        let total = match name {
+            "uninitialized" => &mut self.uninitialized,
+            "stored" => &mut self.stored,
+            "ready" => &mut self.ready,
+            "paused" => &mut self.paused,
+            "error" => &mut self.error,
+            _ => return,
+        };
+        *total += duration;
+    }
+}
+
+/// A single stay in a state, see `StateMachine::visits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Visit {
+    pub kind: StateKind,
+    pub entered: Instant,
+    /// How long the state was occupied, or `None` if it is the current state.
+    pub duration: Option<Duration>,
+}
+
+/// Owns the current state and keeps a history of the states that have been entered.
+pub struct StateMachine {
+    // This is only `None` while a transition is in progress because the transition methods
+    // consume the state.
+    state: Option<State>,
+    created_at: Instant,
+    history: Vec<(&'static str, Instant)>,
+    durations: Durations,
+    visits: Vec<Visit>,
+    rejections: HashMap<&'static str, u64>,
+    // The time during which timing was paused in the current state, excluding the pause that is
+    // in progress, if any.
+    paused_total: Duration,
+    timing_paused_at: Option<Instant>,
+    observers: Vec<Box<dyn TransitionObserver>>,
+    guard: Option<Box<dyn TransitionGuard>>,
+    rate_limit: Option<RateLimit>,
+    // Set while a transition is in progress, which includes notifying the observers.
+    transitioning: bool,
+    // Events are sent over the channel if there is one, and collected otherwise.
+    event_tx: Option<mpsc::Sender<TransitionEvent>>,
+    events: Vec<TransitionEvent>,
+    snapshot_tx: broadcast::Sender<StateSnapshot>,
+}
+
+impl fmt::Debug for StateMachine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StateMachine")
+            .field("state", &self.state)
+            .field("created_at", &self.created_at)
+            .field("history", &self.history)
+            .field("durations", &self.durations)
+            .field("visits", &self.visits)
+            .field("rejections", &self.rejections)
+            .field("paused_total", &self.paused_total)
+            .field("timing_paused_at", &self.timing_paused_at)
+            .field("observers", &self.observers.len())
+            .field("guard", &self.guard.is_some())
+            .field("rate_limit", &self.rate_limit)
+            .field("transitioning", &self.transitioning)
+            .field("event_tx", &self.event_tx)
+            .field("events", &self.events)
+            .field("subscribers", &self.snapshot_tx.receiver_count())
+            .finish()
+    }
+}
+
+impl Default for StateMachine {
+    fn default() -> Self {
+        Self::new(State::default())
+    }
+}
+
+impl StateMachine {
+    pub fn new(state: State) -> Self {
+        Self {
+            created_at: state.clock().now(),
+            history: vec![(state.name(), state.clock().now())],
+            visits: vec![Visit {
+                kind: state.kind(),
+                entered: state.clock().now(),
+                duration: None,
+            }],
+            state: Some(state),
+            durations: Durations::default(),
+            rejections: HashMap::new(),
+            paused_total: Duration::ZERO,
+            timing_paused_at: None,
+            observers: Vec::new(),
+            guard: None,
+            rate_limit: None,
+            transitioning: false,
+            event_tx: None,
+            events: Vec::new(),
+            snapshot_tx: broadcast::channel(SNAPSHOT_CAPACITY).0,
+        }
+    }
+
+    /// Yields a snapshot of the new state after every successful transition from now on. Every
+    /// subscriber receives every snapshot, except that a subscriber that falls too far behind skips
+    /// the snapshots it missed.
+    pub fn subscribe(&mut self) -> impl Stream<Item = StateSnapshot> {
+        BroadcastStream::new(self.snapshot_tx.subscribe()).filter_map(Result::ok)
+    }
+
+    /// Sends a `TransitionEvent` over `tx` for every successful transition from now on, instead
+    /// of collecting them in `events`. Events are dropped with a warning when the channel is full
+    /// or closed.
+    pub fn set_event_sender(&mut self, tx: mpsc::Sender<TransitionEvent>) {
+        self.event_tx = Some(tx);
+    }
+
+    /// The events of the transitions that happened while no event sender was set.
+    pub fn events(&self) -> &[TransitionEvent] {
+        &self.events
+    }
+
+    /// Registers an observer that is notified of every successful transition.
+    pub fn add_observer(&mut self, observer: Box<dyn TransitionObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Consults `guard` before every transition from now on, replacing the previous guard if any.
+    /// The guard is not consulted by `try_initialize`, `poll_timeout` and `reset`, and it is
+    /// consulted before the rate limit, see `set_rate_limit`.
+    pub fn set_guard(&mut self, guard: Box<dyn TransitionGuard>) {
+        self.guard = Some(guard);
+    }
+
+    /// Allows at most `max_transitions` transitions within any `window` of time from now on, as
+    /// measured by the clock of the state, replacing the previous limit if any. Like the guard, the
+    /// limit does not apply to `try_initialize`, `poll_timeout` and `reset`, and those transitions
+    /// do not count towards it. Neither do rejected transitions.
+    ///
+    /// Panics if `max_transitions` is zero.
+    pub fn set_rate_limit(&mut self, max_transitions: usize, window: Duration) {
+        assert!(
+            max_transitions > 0,
+            "the rate limit should allow transitions"
+        );
+        self.rate_limit = Some(RateLimit {
+            max_transitions,
+            window,
+            recent: VecDeque::with_capacity(max_transitions),
+        });
+    }
+
+    pub fn current(&self) -> &State {
+        self.state
+            .as_ref()
+            .expect("state should be present outside of transitions")
+    }
+
+    /// The names of the states that have been entered, including the initial state, in the order
+    /// in which they were entered.
+    pub fn history(&self) -> &[(&'static str, Instant)] {
+        &self.history
+    }
+
+    /// Every visit to a state in the order in which they happened, including the visit to the
+    /// current state.
+    pub fn visits(&self) -> impl Iterator<Item = Visit> + '_ {
+        self.visits.iter().copied()
+    }
+
+    /// Stops counting time towards the current state, for example while the process is suspended,
+    /// until `resume_timing` is called. Pausing while already paused does nothing. The pause
+    /// carries over into the next state when transitioning while paused.
+    pub fn pause_timing(&mut self) {
+        if self.timing_paused_at.is_none() {
+            self.timing_paused_at = Some(self.current().clock().now());
+        }
+    }
+
+    /// Starts counting time towards the current state again. Resuming without pausing first does
+    /// nothing.
+    pub fn resume_timing(&mut self) {
+        if let Some(paused_at) = self.timing_paused_at.take() {
+            let now = self.current().clock().now();
+            self.paused_total += now.saturating_duration_since(paused_at);
+        }
+    }
+
+    /// How long we have been in the current state so far, excluding the time during which timing
+    /// was paused.
+    pub fn elapsed(&self) -> Duration {
+        let current = self.current();
+        let paused = match self.timing_paused_at {
+            Some(paused_at) => {
+                self.paused_total + current.clock().now().saturating_duration_since(paused_at)
+            }
+            None => self.paused_total,
+        };
+        current.elapsed().saturating_sub(paused)
+    }
+
+    /// How long ago the machine was created, regardless of the states it went through since.
+    pub fn uptime(&self) -> Duration {
+        self.current()
+            .clock()
+            .now()
+            .saturating_duration_since(self.created_at)
+    }
+
+    /// The time spent in each state over all completed visits.
+    pub fn durations(&self) -> &Durations {
+        &self.durations
+    }
+
+    /// The mean duration of the completed visits to `kind`, or `None` if there were none. The visit
+    /// to the current state is not included because it has not ended yet.
+    pub fn average_time(&self, kind: StateKind) -> Option<Duration> {
+        let durations: Vec<Duration> = self
+            .visits
+            .iter()
+            .filter(|visit| visit.kind == kind)
+            .filter_map(|visit| visit.duration)
+            .collect();
+        let count = u32::try_from(durations.len())
+            .ok()
+            .filter(|&count| count > 0)?;
+        Some(durations.iter().sum::<Duration>() / count)
+    }
+
+    /// The total time spent in the state with the given name. The time spent in the current state
+    /// so far is only included when `include_current` is set.
+    pub fn time_in(&self, name: &str, include_current: bool) -> Duration {
+        let mut total = self.durations.get(name);
+        let current = self.current();
+        if include_current && current.name() == name {
+            total += self.elapsed();
+        }
+        total
+    }
+
+    /// How often `op` was rejected because it was not legal from the current state. Operations
+    /// denied by the guard are not counted.
+    pub fn rejection_count(&self, op: &str) -> u64 {
+        self.rejections.get(op).copied().unwrap_or(0)
+    }
+
+    /// The number of successful transitions. Rejected transitions are not counted.
+    pub fn transition_count(&self) -> u64 {
+        self.current().transition_count()
+    }
+
+    /// Renders the metrics of the state machine in the Prometheus text exposition format. The
+    /// time spent in each state includes the time spent in the current state so far.
+    pub fn metrics_text(&self) -> String {
+        let mut text = String::new();
+        // Writing to a `String` can not fail.
+        _ = writeln!(
+            text,
+            "# HELP state_ready_count The current ready count of the state machine."
+        );
+        _ = writeln!(text, "# TYPE state_ready_count gauge");
+        _ = writeln!(text, "state_ready_count {}", self.current().ready_count());
+        _ = writeln!(
+            text,
+            "# HELP state_transitions_total The number of successful transitions."
+        );
+        _ = writeln!(text, "# TYPE state_transitions_total counter");
+        _ = writeln!(text, "state_transitions_total {}", self.transition_count());
+        _ = writeln!(
+            text,
+            "# HELP state_time_seconds The total time spent in each state."
+        );
+        _ = writeln!(text, "# TYPE state_time_seconds counter");
+        for name in STATES {
+            _ = writeln!(
+                text,
+                "state_time_seconds{{state=\"{}\"}} {}",
+                escape_label_value(name),
+                self.time_in(name, true).as_secs_f64()
+            );
+        }
+        text
+    }
+
+    /// See `State::initialize`. The guard and the rate limit are not consulted.
+    pub fn try_initialize(&mut self, config: Config) -> Result<(), TransitionError> {
+        self.transition("initialize", |state| state.initialize(config))
+    }
+
+    pub fn try_ready(&mut self) -> Result<(), TransitionError> {
+        self.guarded(Operation::Ready, State::ready)
+    }
+
+    pub fn try_store(&mut self) -> Result<(), TransitionError> {
+        self.guarded(Operation::Store, State::store)
+    }
+
+    pub fn try_pause(&mut self) -> Result<(), TransitionError> {
+        self.guarded(Operation::Pause, State::pause)
+    }
+
+    pub fn try_resume(&mut self) -> Result<(), TransitionError> {
+        self.guarded(Operation::Resume, State::resume)
+    }
+
+    pub fn try_recover(&mut self) -> Result<(), TransitionError> {
+        self.guarded(Operation::Recover, State::recover)
+    }
+
+    pub fn try_rearm(&mut self) -> Result<(), TransitionError> {
+        self.guarded(Operation::Rearm, State::rearm)
+    }
+
+    /// Dispatches to the transition method for `op`.
+    pub fn try_apply(&mut self, op: Operation) -> Result<(), TransitionError> {
+        match op {
+            Operation::Ready => self.try_ready(),
+            Operation::Store => self.try_store(),
+            Operation::Pause => self.try_pause(),
+            Operation::Resume => self.try_resume(),
+            Operation::Recover => self.try_recover(),
+            Operation::Rearm => self.try_rearm(),
+            Operation::Reset => {
+                self.reset();
+                Ok(())
+            }
+        }
+    }
+
+    /// See `State::poll_timeout`. Returns whether the state was stored, the guard and the rate limit
+    /// are not consulted.
+    pub fn poll_timeout(&mut self) -> bool {
+        if self.transitioning || self.current().time_until_auto_store() != Some(Duration::ZERO) {
+            return false;
+        }
+        self.transition("store", |state| Ok(state.poll_timeout()))
+            .expect("polling the timeout should always succeed");
+        true
+    }
+
+    /// See `State::exit`. The observers are notified of leaving the current state.
+    pub fn exit(mut self) -> Duration {
+        let state = self
+            .state
+            .take()
+            .expect("state should be present outside of transitions");
+        let from = state.name();
+        let time_in_state = state.exit();
+        for observer in &self.observers {
+            observer.on_exit(from);
+        }
+        time_in_state
+    }
+
+    /// See `State::reset`. This always succeeds because the guard and the rate limit are not
+    /// consulted. Panics when called while another transition is in progress.
+    pub fn reset(&mut self) {
+        self.transition("reset", |state| Ok(state.reset()))
+            .expect("resetting should always succeed");
+    }
+
+    /// Like `transition`, but rejects legal operations that the guard does not allow or that
+    /// exceed the rate limit.
+    fn guarded(
+        &mut self,
+        op: Operation,
+        f: impl FnOnce(State) -> Result<State, (State, TransitionError)>,
+    ) -> Result<(), TransitionError> {
+        self.check_reentrancy(op.name())?;
+        let current = self
+            .state
+            .as_ref()
+            .expect("state should be present outside of transitions");
+        // Illegal operations are left to `f` so they are reported as such.
+        if !current.can(op) {
+            return self.transition(op.name(), f);
+        }
+        if let Some(guard) = &self.guard {
+            if !guard.allow(current.kind(), op) {
+                return Err(TransitionError::Denied {
+                    operation: op.name(),
+                    state: current.name(),
+                });
+            }
+        }
+        let now = current.clock().now();
+        if let Some(retry_after) = self.rate_limit.as_mut().and_then(|limit| limit.check(now)) {
+            return Err(TransitionError::RateLimited {
+                operation: op.name(),
+                state: current.name(),
+                retry_after,
+            });
+        }
+        self.transition(op.name(), f)?;
+        if let Some(limit) = &mut self.rate_limit {
+            limit.recent.push_back(now);
+        }
+        Ok(())
+    }
+
+    /// Rejects a transition that is attempted while another one is in progress. The state is
+    /// taken out of the machine during a transition, so the name of the state being left is
+    /// looked up in the history instead.
+    fn check_reentrancy(&self, op: &'static str) -> Result<(), TransitionError> {
+        if !self.transitioning {
+            return Ok(());
+        }
+        let &(state, _) = self
+            .history
+            .last()
+            .expect("the history should contain the initial state");
+        tracing::warn!(operation = op, state, "Rejected a nested {op} transition.");
+        Err(TransitionError::Reentrant {
+            operation: op,
+            state,
+        })
+    }
+
+    fn transition(
+        &mut self,
+        op: &'static str,
+        f: impl FnOnce(State) -> Result<State, (State, TransitionError)>,
+    ) -> Result<(), TransitionError> {
+        self.check_reentrancy(op)?;
+        let elapsed = self.elapsed();
+        let state = self
+            .state
+            .take()
+            .expect("state should be present outside of transitions");
+        let from = state.name();
+        self.transitioning = true;
+
+        let result = match f(state) {
+            Ok(state) => {
+                self.durations.add(from, elapsed);
+                let to = state.name();
+                for observer in &self.observers {
+                    observer.on_exit(from);
+                }
+                for observer in &self.observers {
+                    observer.on_enter(to);
+                }
+                self.paused_total = Duration::ZERO;
+                if self.timing_paused_at.is_some() {
+                    self.timing_paused_at = Some(state.clock().now());
+                }
+                self.history.push((to, state.clock().now()));
+                if let Some(visit) = self.visits.last_mut() {
+                    visit.duration = Some(elapsed);
+                }
+                self.visits.push(Visit {
+                    kind: state.kind(),
+                    entered: state.clock().now(),
+                    duration: None,
+                });
+                // Sending only fails when there are no subscribers.
+                _ = self.snapshot_tx.send(state.snapshot());
+                self.emit(TransitionEvent {
+                    timestamp: SystemTime::now(),
+                    from,
+                    to,
+                    op,
+                    ready_count: state.ready_count(),
+                });
+                self.state = Some(state);
+                Ok(())
+            }
+            Err((state, error)) => {
+                if let TransitionError::Illegal { operation, .. } = error {
+                    *self.rejections.entry(operation).or_default() += 1;
+                }
+                self.state = Some(state);
+                Err(error)
+            }
+        };
+        self.transitioning = false;
+        result
+    }
+
+    fn emit(&mut self, event: TransitionEvent) {
+        match &self.event_tx {
+            Some(tx) => {
+                if let Err(error) = tx.try_send(event) {
+                    tracing::warn!("Dropped transition event: {error}.");
+                }
+            }
+            None => self.events.push(event),
+        }
+    }
+}
+
+/// Escapes a Prometheus label value, which is written between double quotes.
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TransitionObserver for RecordingObserver {
+        fn on_exit(&self, from: &'static str) {
+            self.calls.lock().unwrap().push(format!("exit {from}"));
+        }
+
+        fn on_enter(&self, to: &'static str) {
+            self.calls.lock().unwrap().push(format!("enter {to}"));
+        }
+    }
+
+    #[test]
+    fn records_history() {
+        let mut machine = StateMachine::new(State::stored_with(0));
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        machine
+            .try_store()
+            .expect("should be able to transition from ready to stored");
+
+        let names: Vec<_> = machine.history().iter().map(|&(name, _)| name).collect();
+        assert_eq!(vec!["stored", "ready", "stored"], names);
+        assert!(
+            machine
+                .history()
+                .windows(2)
+                .all(|pair| pair[0].1 <= pair[1].1),
+            "history should be ordered by time"
+        );
+    }
+
+    #[test]
+    fn rearm_stays_ready() {
+        let mut machine = StateMachine::new(State::ready_with(1));
+        machine
+            .try_rearm()
+            .expect("should be able to rearm the ready state");
+        machine
+            .try_apply(Operation::Rearm)
+            .expect("should be able to rearm the ready state");
+        assert_eq!(3, machine.current().ready_count());
+        assert_eq!(2, machine.transition_count());
+        let names: Vec<_> = machine.history().iter().map(|&(name, _)| name).collect();
+        assert_eq!(vec!["ready", "ready", "ready"], names);
+
+        machine
+            .try_store()
+            .expect("should be able to transition from ready to stored");
+        let error = machine
+            .try_rearm()
+            .expect_err("can only rearm the ready state");
+        assert_eq!("stored", error.state());
+        assert_eq!(1, machine.rejection_count("rearm"));
+    }
+
+    #[test]
+    fn starts_uninitialized() {
+        let mut machine = StateMachine::default();
+        machine
+            .try_ready()
+            .expect_err("can not transition before initializing");
+        machine
+            .try_initialize(Config::default())
+            .expect("should be able to initialize");
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        let names: Vec<_> = machine.history().iter().map(|&(name, _)| name).collect();
+        assert_eq!(vec!["uninitialized", "stored", "ready"], names);
+    }
+
+    #[test]
+    fn counts_successful_transitions() {
+        let mut machine = StateMachine::new(State::stored_with(0));
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        machine
+            .try_ready()
+            .expect_err("can not transition from ready to ready");
+        machine
+            .try_store()
+            .expect("should be able to transition from ready to stored");
+        assert_eq!(2, machine.transition_count());
+    }
+
+    #[test]
+    fn rejected_transition_is_not_recorded() {
+        let mut machine = StateMachine::new(State::stored_with(0));
+        let error = machine
+            .try_store()
+            .expect_err("can not transition from stored to stored");
+        assert_eq!("store", error.operation());
+        assert_eq!("stored", machine.current().name());
+        assert_eq!(1, machine.history().len());
+    }
+
+    #[test]
+    fn counts_rejections_per_operation() {
+        let mut machine = StateMachine::new(State::stored_with(0));
+        for _ in 0..2 {
+            machine
+                .try_store()
+                .expect_err("can not transition from stored to stored");
+        }
+        assert_eq!(2, machine.rejection_count("store"));
+        assert_eq!(0, machine.rejection_count("ready"));
+
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        assert_eq!(0, machine.rejection_count("ready"));
+    }
+
+    #[test]
+    fn notifies_observers() {
+        let observer = RecordingObserver::default();
+        let calls = Arc::clone(&observer.calls);
+        let mut machine = StateMachine::new(State::stored_with(0));
+        machine.add_observer(Box::new(observer));
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        machine
+            .try_store()
+            .expect("should be able to transition from ready to stored");
+        machine
+            .try_store()
+            .expect_err("can not transition from stored to stored");
+
+        assert_eq!(
+            vec!["exit stored", "enter ready", "exit ready", "enter stored"],
+            *calls.lock().unwrap()
+        );
+    }
+
+    struct DenyStore;
+
+    impl TransitionGuard for DenyStore {
+        fn allow(&self, _from: StateKind, op: Operation) -> bool {
+            op != Operation::Store
+        }
+    }
+
+    #[test]
+    fn guard_vetoes_transitions() {
+        let mut machine = StateMachine::new(State::stored_with(0));
+        machine.set_guard(Box::new(DenyStore));
+        let error = machine
+            .try_store()
+            .expect_err("can not transition from stored to stored");
+        assert!(
+            matches!(error, TransitionError::Illegal { .. }),
+            "the legality check should run before the guard"
+        );
+        machine
+            .try_ready()
+            .expect("the guard should allow transitioning from stored to ready");
+        let error = machine
+            .try_store()
+            .expect_err("the guard should deny storing");
+        assert_eq!(
+            TransitionError::Denied {
+                operation: "store",
+                state: "ready",
+            },
+            error
+        );
+        assert_eq!("ready", machine.current().name());
+        assert_eq!(2, machine.history().len());
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_snapshots() {
+        let mut machine = StateMachine::new(State::stored_with(0));
+        let mut first = Box::pin(machine.subscribe());
+        let mut second = Box::pin(machine.subscribe());
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        machine
+            .try_store()
+            .expect("should be able to transition from ready to stored");
+        drop(machine);
+
+        for subscriber in [&mut first, &mut second] {
+            let snapshots: Vec<_> = subscriber
+                .map(|snapshot| (snapshot.name, snapshot.ready_count))
+                .collect()
+                .await;
+            assert_eq!(vec![("ready", 1), ("stored", 1)], snapshots);
+        }
+    }
+
+    #[test]
+    fn collects_events_without_sender() {
+        let mut machine = StateMachine::new(State::stored_with(0));
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        let events = machine.events();
+        assert_eq!(1, events.len());
+        assert_eq!(
+            ("stored", "ready", "ready"),
+            (events[0].from, events[0].op, events[0].to)
+        );
+        assert_eq!(1, events[0].ready_count);
+    }
+
+    #[test]
+    fn sends_events() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut machine = StateMachine::new(State::stored_with(0));
+        machine.set_event_sender(tx);
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        machine
+            .try_store()
+            .expect("should be able to transition from ready to stored");
+        machine
+            .try_store()
+            .expect_err("can not transition from stored to stored");
+
+        let first = rx
+            .try_recv()
+            .expect("the first event should have been sent");
+        assert_eq!(("stored", "ready"), (first.from, first.to));
+        let second = rx
+            .try_recv()
+            .expect("the second event should have been sent");
+        assert_eq!(("ready", "stored"), (second.from, second.to));
+        assert_eq!("store", second.op);
+        assert!(first.timestamp <= second.timestamp);
+        assert!(rx.try_recv().is_err(), "rejected transitions have no event");
+        assert!(machine.events().is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn event_serializes_to_one_json_line() {
+        let event = TransitionEvent {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(7),
+            from: "stored",
+            to: "ready",
+            op: "ready",
+            ready_count: 1,
+        };
+        let json = serde_json::to_string(&event).expect("event should serialize");
+        assert!(!json.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&json).expect("should be valid JSON");
+        assert_eq!("stored", value["from"]);
+        assert_eq!("ready", value["to"]);
+        assert_eq!("ready", value["op"]);
+        assert_eq!(1, value["ready_count"]);
+        assert_eq!(7, value["timestamp"]["secs_since_epoch"]);
+    }
+
+    #[test]
+    fn metrics_text() {
+        let mut machine = StateMachine::new(State::stored_with(0));
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+
+        let text = machine.metrics_text();
+        assert!(text.contains("\nstate_ready_count 1\n"), "{text}");
+        assert!(text.contains("\nstate_transitions_total 1\n"), "{text}");
+        assert!(
+            text.contains("\nstate_time_seconds{state=\"ready\"} "),
+            "{text}"
+        );
+        assert!(text.ends_with('\n'));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(r#"a\"b\\c\nd"#, escape_label_value("a\"b\\c\nd"));
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn records_visits() {
+        let mut machine = StateMachine::new(State::stored_with(0));
+        std::thread::sleep(Duration::from_millis(2));
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        std::thread::sleep(Duration::from_millis(2));
+        machine
+            .try_store()
+            .expect("should be able to transition from ready to stored");
+
+        let visits: Vec<_> = machine.visits().collect();
+        let kinds: Vec<_> = visits.iter().map(|visit| visit.kind).collect();
+        assert_eq!(
+            vec![StateKind::Stored, StateKind::Ready, StateKind::Stored],
+            kinds
+        );
+        for visit in &visits[..2] {
+            let duration = visit.duration.expect("past visits should have a duration");
+            assert!(duration >= Duration::from_millis(2), "{duration:?}");
+        }
+        assert_eq!(None, visits[2].duration);
+        assert!(visits
+            .windows(2)
+            .all(|pair| pair[0].entered <= pair[1].entered));
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn accumulates_time_in_states() {
+        let mut machine = StateMachine::new(State::stored_with(0));
+        std::thread::sleep(Duration::from_millis(2));
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        std::thread::sleep(Duration::from_millis(2));
+        machine
+            .try_store()
+            .expect("should be able to transition from ready to stored");
+        std::thread::sleep(Duration::from_millis(2));
+
+        assert!(machine.time_in("stored", false) >= Duration::from_millis(2));
+        assert!(machine.time_in("ready", false) >= Duration::from_millis(2));
+        assert!(machine.time_in("stored", true) >= Duration::from_millis(4));
+        assert_eq!(
+            machine.time_in("ready", false),
+            machine.time_in("ready", true),
+            "the current state is stored so its time should not be added to ready"
+        );
+        assert_eq!(Duration::ZERO, machine.time_in("paused", true));
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn paused_timing_is_excluded_from_elapsed() {
+        let clock = MockClock::new();
+        let state = State::Stored(StoredState::enter(StoredStateInputs {
+            clock: Arc::new(clock.clone()),
+            ..StoredStateInputs::new(0)
+        }));
+        let mut machine = StateMachine::new(state);
+
+        machine.resume_timing();
+        clock.advance(Duration::from_secs(1));
+        machine.pause_timing();
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(Duration::from_secs(1), machine.elapsed());
+        machine.resume_timing();
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(Duration::from_secs(3), machine.elapsed());
+        assert_eq!(Duration::from_secs(13), machine.current().elapsed());
+
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        assert_eq!(Duration::from_secs(3), machine.durations().stored);
+        assert_eq!(Duration::ZERO, machine.elapsed());
+    }
+
+    #[test]
+    fn uptime_spans_all_states() {
+        let clock = MockClock::new();
+        let state = State::Stored(StoredState::enter(StoredStateInputs {
+            clock: Arc::new(clock.clone()),
+            ..StoredStateInputs::new(0)
+        }));
+        let mut machine = StateMachine::new(state);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(Duration::from_secs(2), machine.uptime());
+
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Duration::from_secs(3), machine.uptime());
+        assert!(machine.uptime() > machine.elapsed());
+        assert!(machine.uptime() > machine.durations().stored);
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn average_time_of_completed_visits() {
+        let clock = MockClock::new();
+        let state = State::Stored(StoredState::enter(StoredStateInputs {
+            clock: Arc::new(clock.clone()),
+            ..StoredStateInputs::new(0)
+        }));
+        let mut machine = StateMachine::new(state);
+        assert_eq!(None, machine.average_time(StateKind::Stored));
+
+        for seconds in [1, 3] {
+            clock.advance(Duration::from_secs(seconds));
+            machine
+                .try_ready()
+                .expect("should be able to transition from stored to ready");
+            machine
+                .try_store()
+                .expect("should be able to transition from ready to stored");
+        }
+        clock.advance(Duration::from_secs(100));
+
+        assert_eq!(
+            Some(Duration::from_secs(2)),
+            machine.average_time(StateKind::Stored),
+            "the current visit should not count"
+        );
+        assert_eq!(Some(Duration::ZERO), machine.average_time(StateKind::Ready));
+        assert_eq!(None, machine.average_time(StateKind::Paused));
+    }
+
+    #[test]
+    fn rate_limit_rejects_until_the_window_passes() {
+        let clock = MockClock::new();
+        let state = State::Stored(StoredState::enter(StoredStateInputs {
+            clock: Arc::new(clock.clone()),
+            ..StoredStateInputs::new(0)
+        }));
+        let mut machine = StateMachine::new(state);
+        machine.set_rate_limit(2, Duration::from_secs(10));
+
+        machine
+            .try_ready()
+            .expect("the first transition should be within the limit");
+        clock.advance(Duration::from_secs(4));
+        machine
+            .try_store()
+            .expect("the second transition should be within the limit");
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(
+            Err(TransitionError::RateLimited {
+                operation: "ready",
+                state: "stored",
+                retry_after: Duration::from_secs(4),
+            }),
+            machine.try_ready()
+        );
+        machine
+            .try_pause()
+            .expect_err("illegal operations should be rejected as such");
+        assert_eq!(1, machine.rejection_count("pause"));
+
+        clock.advance(Duration::from_secs(4));
+        machine
+            .try_ready()
+            .expect("the first transition should have left the window");
+        assert_eq!(
+            Some(Duration::from_secs(4)),
+            match machine.try_store() {
+                Err(TransitionError::RateLimited { retry_after, .. }) => Some(retry_after),
+                _ => None,
+            }
+        );
+    }
+
+    #[test]
+    fn nested_transitions_are_rejected() {
+        let mut machine = StateMachine::new(State::stored_with(0));
+        // Safe code can not call back into the machine while it notifies its observers, so this
+        // sets the flag the way an observer would find it during `on_enter`.
+        machine.transitioning = true;
+        assert_eq!(
+            Err(TransitionError::Reentrant {
+                operation: "ready",
+                state: "stored",
+            }),
+            machine.try_ready()
+        );
+        machine
+            .try_initialize(Config::default())
+            .expect_err("initialize should be rejected too");
+        assert!(!machine.poll_timeout());
+        assert_eq!(0, machine.rejection_count("ready"));
+        machine.transitioning = false;
+
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        assert!(!machine.transitioning, "the flag should be cleared");
+        machine
+            .try_ready()
+            .expect_err("can not transition from ready to ready");
+        assert!(!machine.transitioning, "the flag should be cleared");
+        assert_eq!(2, machine.history().len());
+    }
+}
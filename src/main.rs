@@ -1,64 +1,191 @@
-use plain_state_machine_hype_train::State;
+use plain_state_machine_hype_train::{
+    prompt, run_with_ready_count, Command, Config, MachineId, Operation,
+};
 use std::io::Write;
+use std::time::Duration;
 use tokio::sync::{mpsc, oneshot};
 use tracing::info;
 
-pub enum Command {
-    Ready(oneshot::Sender<String>),
-    Store(oneshot::Sender<String>),
-}
-
 fn read_stdin_thread(tx: mpsc::Sender<Command>) {
     use std::io::BufRead;
 
     let mut lines = std::io::stdin().lock().lines();
 
-    loop {
-        print!("Please enter an operation: ready, store\n> ");
+    // The prompt offers the operations of the machine that was used last. The loop stops when
+    // `run` has shut down, for example after ctrl-c, and closed its end of the channel.
+    let mut id = MachineId::default();
+    while let Some(mut operations) = legal_operations(&tx, id) {
+        print!("{}", prompt(&operations));
         std::io::stdout().lock().flush().unwrap();
-        let line = lines.next().unwrap().unwrap();
+        // Closing stdin drops `tx`, which stops `run`.
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line.unwrap();
+        // An operation may be prefixed with the id of the machine to apply it to.
+        let line = match line.trim().split_once(' ') {
+            Some((prefix, rest)) => match prefix.parse() {
+                Ok(prefix) => {
+                    if id != MachineId(prefix) {
+                        id = MachineId(prefix);
+                        let Some(prefixed) = legal_operations(&tx, id) else {
+                            break;
+                        };
+                        operations = prefixed;
+                    }
+                    rest.trim()
+                }
+                Err(_) => line.trim(),
+            },
+            None => line.trim(),
+        };
         let (response_tx, response_rx) = oneshot::channel();
-        let command = match line.as_str() {
-            "ready" => Command::Ready(response_tx),
-            "store" => Command::Store(response_tx),
-            _ => {
-                println!("unknown command, try again");
+        let command = if line.eq_ignore_ascii_case("status") {
+            Command::Status {
+                id,
+                respond: response_tx,
+            }
+        } else if line.eq_ignore_ascii_case("history") {
+            Command::History {
+                id,
+                respond: response_tx,
+            }
+        } else if line.eq_ignore_ascii_case("help") {
+            Command::Help {
+                respond: response_tx,
+            }
+        } else {
+            let op = match line.parse::<Operation>() {
+                Ok(op) => op,
+                Err(error) => {
+                    println!("{error}");
+                    continue;
+                }
+            };
+            if !operations.contains(&op) {
+                println!("Can not {} {id} right now.", op.name());
                 continue;
             }
+            Command::Apply {
+                id,
+                op,
+                respond: response_tx,
+            }
         };
-        tx.blocking_send(command).unwrap();
-        println!("{}", response_rx.blocking_recv().unwrap());
+        if tx.blocking_send(command).is_err() {
+            break;
+        }
+        let Ok(response) = response_rx.blocking_recv() else {
+            break;
+        };
+        println!("{response}");
+    }
+}
+
+/// Asks `run` which operations are legal for the machine with the given id, or `None` once `run`
+/// has shut down.
+fn legal_operations(tx: &mpsc::Sender<Command>, id: MachineId) -> Option<Vec<Operation>> {
+    let (respond, response_rx) = oneshot::channel();
+    tx.blocking_send(Command::Operations { id, respond }).ok()?;
+    response_rx.blocking_recv().ok()
+}
+
+const USAGE: &str = "usage: plain-state-machine-hype-train [READY_COUNT]";
+
+/// Parses the optional ready count that the state machines start out with from the arguments
+/// that follow the program name. It defaults to 0.
+fn parse_ready_count(mut args: impl Iterator<Item = String>) -> Result<u64, String> {
+    let ready_count = match args.next() {
+        Some(arg) => arg
+            .parse()
+            .map_err(|error| format!("invalid ready count {arg:?}: {error}"))?,
+        None => 0,
+    };
+    if let Some(arg) = args.next() {
+        return Err(format!("unexpected argument {arg:?}"));
     }
+    Ok(ready_count)
+}
+
+/// Parses the value of `AUTO_STORE_AFTER_SECS`, which makes the ready state store itself after
+/// being idle for that many seconds. Leaving it unset disables storing automatically.
+fn parse_auto_store_after(secs: Option<String>) -> Result<Option<Duration>, String> {
+    let Some(secs) = secs else {
+        return Ok(None);
+    };
+    let parsed = secs
+        .parse()
+        .map_err(|error| format!("invalid AUTO_STORE_AFTER_SECS {secs:?}: {error}"))?;
+    Duration::try_from_secs_f64(parsed)
+        .map(Some)
+        .map_err(|error| format!("invalid AUTO_STORE_AFTER_SECS {secs:?}: {error}"))
 }
 
 #[tokio::main]
 async fn main() {
+    let exit_with_usage = |error: String| -> ! {
+        eprintln!("{error}\n{USAGE}");
+        std::process::exit(2);
+    };
+    let ready_count =
+        parse_ready_count(std::env::args().skip(1)).unwrap_or_else(|error| exit_with_usage(error));
+    let auto_store_after = parse_auto_store_after(std::env::var("AUTO_STORE_AFTER_SECS").ok())
+        .unwrap_or_else(|error| exit_with_usage(error));
+
     tracing_subscriber::fmt().init();
 
     info!("Started operations");
 
-    let (tx, mut rx) = mpsc::channel::<Command>(1);
+    let (tx, rx) = mpsc::channel::<Command>(1);
 
     _ = std::thread::spawn(move || read_stdin_thread(tx));
 
-    let mut state = State::default();
-    while let Some(command) = rx.recv().await {
-        let (tx, result) = match command {
-            Command::Ready(tx) => (tx, state.ready()),
-            Command::Store(tx) => (tx, state.store()),
-        };
-        state = match result {
-            Ok(state) => {
-                let _ = tx.send(format!("Transitioned to {}!", state.name()));
-                state
-            }
-            Err(state) => {
-                let _ = tx.send(format!(
-                    "Transition failed! Current state is {}.",
-                    state.name()
-                ));
-                state
-            }
+    let config = Config {
+        auto_store_after,
+        ..Config::default()
+    };
+
+    // Interrupting the process exits the current states before quitting.
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            _ = shutdown_tx.send(());
         }
+    });
+
+    // The machines are handed back when stdin closes. Exiting them runs their `exit` code, and
+    // dropping them instead would warn about every one of them.
+    let machines = run_with_ready_count(config, ready_count, rx, shutdown_rx).await;
+    for (id, machine) in machines {
+        info!("Shutting down {id}.");
+        machine.exit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<u64, String> {
+        parse_ready_count(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_ready_count() {
+        assert_eq!(Ok(3), parse(&["3"]));
+        assert_eq!(Ok(0), parse(&[]), "the ready count defaults to 0");
+        assert!(parse(&["three"]).is_err());
+        assert!(parse(&["-1"]).is_err());
+        assert!(parse(&["1", "2"]).is_err());
+    }
+
+    #[test]
+    fn parses_auto_store_after() {
+        let parse = |secs: Option<&str>| parse_auto_store_after(secs.map(str::to_string));
+        assert_eq!(Ok(None), parse(None));
+        assert_eq!(Ok(Some(Duration::from_millis(1500))), parse(Some("1.5")));
+        assert!(parse(Some("soon")).is_err());
+        assert!(parse(Some("-1")).is_err());
+        assert!(parse(Some("inf")).is_err());
     }
 }
@@ -0,0 +1,566 @@
+use crate::*;
+use std::fmt;
+use std::str::FromStr;
+
+/// The operations that can be applied to a `State`. This describes *what* to do, independent of
+/// where the request came from or how the outcome is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Ready,
+    Store,
+    Pause,
+    Resume,
+    Recover,
+    Rearm,
+    Reset,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 7] = [
+        Operation::Ready,
+        Operation::Store,
+        Operation::Pause,
+        Operation::Resume,
+        Operation::Recover,
+        Operation::Rearm,
+        Operation::Reset,
+    ];
+
+    /// The number of operations, which is the number of columns of `StateKind::TRANSITIONS`.
+    pub const COUNT: usize = Operation::ALL.len();
+
+    /// A one-line description of each operation for humans, indexed by `op as usize`.
+    pub const DESCRIPTIONS: [&'static str; Operation::COUNT] = [
+        "Enters the ready state from the stored state, incrementing the ready count.",
+        "Stores the ready or paused state.",
+        "Pauses the ready state.",
+        "Resumes the paused state.",
+        "Stores a recoverable error state.",
+        "Re-enters the ready state from the ready state, incrementing the ready count.",
+        "Starts over from the stored state with a ready count of 0.",
+    ];
+
+    /// The description of this operation, see `Operation::DESCRIPTIONS`.
+    pub const fn description(self) -> &'static str {
+        Operation::DESCRIPTIONS[self as usize]
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Operation::Ready => "ready",
+            Operation::Store => "store",
+            Operation::Pause => "pause",
+            Operation::Resume => "resume",
+            Operation::Recover => "recover",
+            Operation::Rearm => "rearm",
+            Operation::Reset => "reset",
+        }
+    }
+
+    /// The operation with exactly this name. Unlike parsing, this does not ignore case or
+    /// surrounding whitespace, which lets it be used in `const` contexts.
+    pub const fn from_name(name: &str) -> Option<Operation> {
+        let mut index = 0;
+        while index < Operation::COUNT {
+            let op = Operation::ALL[index];
+            if names_equal(op.name(), name) {
+                return Some(op);
+            }
+            index += 1;
+        }
+        None
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseOperationError(String);
+
+impl fmt::Display for ParseOperationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = Operation::ALL.iter().map(|op| op.name()).collect();
+        write!(
+            f,
+            "unknown operation {:?}, try one of: {}",
+            self.0,
+            names.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for ParseOperationError {}
+
+/// Parses the name of an operation, ignoring case and surrounding whitespace.
+impl FromStr for Operation {
+    type Err = ParseOperationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        Operation::ALL
+            .into_iter()
+            .find(|op| op.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| ParseOperationError(s.to_string()))
+    }
+}
+
+/// Describes a transition made by `State::apply`, so callers do not have to inspect the state
+/// before and after.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransitionReport {
+    pub from: &'static str,
+    pub to: &'static str,
+    pub op: Operation,
+    pub ready_count_before: u64,
+    pub ready_count_after: u64,
+}
+
+impl State {
+    /// Dispatches to the transition method corresponding to `op` and reports what happened.
+    pub fn apply(self, op: Operation) -> Result<(Self, TransitionReport), (Self, TransitionError)> {
+        let from = self.name();
+        let ready_count_before = self.ready_count();
+        let state = match op {
+            Operation::Ready => self.ready(),
+            Operation::Store => self.store(),
+            Operation::Pause => self.pause(),
+            Operation::Resume => self.resume(),
+            Operation::Recover => self.recover(),
+            Operation::Rearm => self.rearm(),
+            Operation::Reset => Ok(self.reset()),
+        }?;
+        let report = TransitionReport {
+            from,
+            to: state.name(),
+            op,
+            ready_count_before,
+            ready_count_after: state.ready_count(),
+        };
+        Ok((state, report))
+    }
+
+    /// Like `apply`, but tags the state with the id of the request that performs the operation. The
+    /// id is logged when the current state is exited and carried forward to the new state. A
+    /// rejected operation leaves the previous id in place.
+    pub fn apply_request(
+        mut self,
+        op: Operation,
+        request_id: u64,
+    ) -> Result<Self, (Self, TransitionError)> {
+        let previous_request_id = self.last_request_id();
+        self.set_last_request_id(Some(request_id));
+        self.apply(op)
+            .map(|(state, _)| state)
+            .map_err(|(mut state, error)| {
+                state.set_last_request_id(previous_request_id);
+                (state, error)
+            })
+    }
+
+    /// Applies each operation in turn and records whether it succeeded. A rejected operation hands
+    /// back the state, so the remaining operations are applied to it regardless. This is useful
+    /// for property testing and replaying recorded sessions.
+    pub fn apply_sequence(self, ops: &[Operation]) -> (Self, Vec<Result<(), ()>>) {
+        let mut state = self;
+        let mut results = Vec::with_capacity(ops.len());
+        for &op in ops {
+            state = match state.apply(op) {
+                Ok((state, _)) => {
+                    results.push(Ok(()));
+                    state
+                }
+                Err((state, _)) => {
+                    results.push(Err(()));
+                    state
+                }
+            };
+        }
+        (state, results)
+    }
+
+    /// Like `apply`, but also returns how long the state machine spent in the state it left.
+    pub fn apply_timed(self, op: Operation) -> Result<(Self, Duration), (Self, TransitionError)> {
+        let (state, _) = self.apply(op)?;
+        let time_in_previous = state.time_in_previous();
+        Ok((state, time_in_previous))
+    }
+
+    /// The operations for which `apply` would currently succeed, in the order of
+    /// `available_operations` followed by `Operation::Reset`.
+    pub fn legal_operations(&self) -> Vec<Operation> {
+        self.available_operations()
+            .iter()
+            .filter_map(|name| name.parse().ok())
+            .chain([Operation::Reset])
+            .filter(|&op| self.can(op))
+            .collect()
+    }
+
+    /// Whether `apply` would currently succeed for `op`, including the guards from the
+    /// configuration like `max_ready_count`. This does not consume the state.
+    pub fn can(&self, op: Operation) -> bool {
+        match (self, op) {
+            (_, Operation::Reset) => true,
+            (State::Stored(state), Operation::Ready) => state
+                .config()
+                .next_ready_count(state.ready_count())
+                .is_some(),
+            (State::Paused(state), Operation::Resume) => state
+                .config()
+                .next_ready_count(state.ready_count())
+                .is_some(),
+            (State::Ready(state), Operation::Rearm) => state
+                .config()
+                .next_ready_count(state.ready_count())
+                .is_some(),
+            _ => self.available_operations().contains(&op.name()),
+        }
+    }
+
+    pub fn can_ready(&self) -> bool {
+        self.can(Operation::Ready)
+    }
+
+    pub fn can_store(&self) -> bool {
+        self.can(Operation::Store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_operation() {
+        for op in Operation::ALL {
+            assert_eq!(Ok(op), op.name().parse());
+        }
+    }
+
+    #[test]
+    fn parse_operation_mixed_case() {
+        assert_eq!(Ok(Operation::Ready), "ReAdY".parse());
+        assert_eq!(Ok(Operation::Store), "STORE".parse());
+    }
+
+    #[test]
+    fn parse_operation_with_whitespace() {
+        assert_eq!(Ok(Operation::Ready), "  ready\t".parse());
+        assert_eq!(Ok(Operation::Store), "\nstore ".parse());
+    }
+
+    #[test]
+    fn parse_invalid_operation() {
+        let error = " jump "
+            .parse::<Operation>()
+            .expect_err("jump is not an operation");
+        assert_eq!(ParseOperationError("jump".to_string()), error);
+        assert_eq!(
+            "unknown operation \"jump\", try one of: ready, store, pause, resume, recover, rearm, reset",
+            error.to_string()
+        );
+        assert!("".parse::<Operation>().is_err());
+    }
+
+    #[test]
+    fn from_name_is_exact() {
+        for op in Operation::ALL {
+            assert_eq!(Some(op), Operation::from_name(op.name()));
+        }
+        assert_eq!(None, Operation::from_name("Ready"));
+        assert_eq!(None, Operation::from_name("initialize"));
+    }
+
+    #[test]
+    fn can_matches_apply() {
+        let states = || {
+            vec![
+                State::stored_with(0),
+                State::ready_with(1),
+                State::ready_with(1).pause().unwrap(),
+                State::stored_with(0).error("test".to_string()),
+                State::stored_with(0).error_with("test".to_string(), false),
+            ]
+        };
+        for index in 0..states().len() {
+            for op in Operation::ALL {
+                let state = states().swap_remove(index);
+                let name = state.name();
+                let can = state.can(op);
+                assert_eq!(state.apply(op).is_ok(), can, "{name} {op:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn apply_request_tags_state() {
+        let state = State::stored_with(0);
+        assert_eq!(None, state.last_request_id());
+        let state = state
+            .apply_request(Operation::Ready, 42)
+            .expect("should be able to transition from stored to ready");
+        assert_eq!(Some(42), state.last_request_id());
+        let (state, _) = state
+            .apply_request(Operation::Ready, 43)
+            .expect_err("can not transition from ready to ready");
+        assert_eq!(Some(42), state.last_request_id());
+        let (state, _) = state.apply(Operation::Store).unwrap();
+        assert_eq!(
+            Some(42),
+            state.last_request_id(),
+            "the id should be carried forward"
+        );
+        assert_eq!(None, State::default().last_request_id());
+    }
+
+    #[test]
+    fn legal_operations_respect_max_ready_count() {
+        let config = Config {
+            max_ready_count: Some(1),
+            ..Config::default()
+        };
+        let state = State::default_with(config);
+        assert_eq!(
+            vec![Operation::Ready, Operation::Reset],
+            state.legal_operations()
+        );
+        let state = state.ready().and_then(State::store).unwrap();
+        assert_eq!(vec![Operation::Reset], state.legal_operations());
+    }
+
+    #[test]
+    fn apply_sequence() {
+        let (state, results) = State::stored_with(0).apply_sequence(&[
+            Operation::Ready,
+            Operation::Store,
+            Operation::Store,
+        ]);
+        assert_eq!(vec![Ok(()), Ok(()), Err(())], results);
+        assert_eq!(StateKind::Stored, state.kind());
+        assert_eq!(1, state.ready_count());
+    }
+
+    #[test]
+    fn apply_empty_sequence() {
+        let (state, results) = State::stored_with(3).apply_sequence(&[]);
+        assert!(results.is_empty());
+        assert_eq!(StateKind::Stored, state.kind());
+        assert_eq!(3, state.ready_count());
+        assert_eq!(0, state.transition_count());
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn apply_timed_returns_time_in_exited_state() {
+        let state = State::stored_with(0);
+        std::thread::sleep(Duration::from_millis(5));
+        let (state, time_in_stored) = state
+            .apply_timed(Operation::Ready)
+            .expect("should be able to transition from stored to ready");
+        assert!(time_in_stored >= Duration::from_millis(5));
+        assert_eq!(Some("stored"), state.previous());
+
+        std::thread::sleep(Duration::from_millis(5));
+        let (state, time_in_ready) = state
+            .apply_timed(Operation::Reset)
+            .expect("resetting should always succeed");
+        assert!(time_in_ready >= Duration::from_millis(5));
+        assert_eq!(Some("ready"), state.previous());
+    }
+
+    #[test]
+    fn can_from_stored() {
+        let state = State::stored_with(0);
+        assert!(state.can_ready());
+        assert!(!state.can_store());
+        assert_eq!("stored", state.name(), "checking should not transition");
+    }
+
+    #[test]
+    fn can_not_ready_at_max_ready_count() {
+        let state = State::default_with(Config {
+            max_ready_count: Some(1),
+            ..Config::default()
+        })
+        .ready()
+        .unwrap()
+        .store()
+        .unwrap();
+        assert!(!state.can_ready());
+        state
+            .ready()
+            .expect_err("should not be able to exceed the limit");
+    }
+
+    #[test]
+    fn can_not_resume_at_max_ready_count() {
+        let state = State::default_with(Config {
+            max_ready_count: Some(1),
+            ..Config::default()
+        })
+        .ready()
+        .and_then(State::pause)
+        .unwrap();
+        assert!(!state.can(Operation::Resume));
+        assert_eq!(
+            vec![Operation::Store, Operation::Reset],
+            state.legal_operations()
+        );
+        let (state, error) = state
+            .resume()
+            .expect_err("should not be able to exceed the limit");
+        assert_eq!("resume", error.operation());
+        assert_eq!(1, state.ready_count());
+        let state = state
+            .ensure_ready()
+            .expect_err("should not be able to exceed the limit");
+        assert_eq!(StateKind::Paused, state.kind());
+    }
+
+    #[test]
+    fn apply_from_stored() {
+        let apply = |op| {
+            State::stored_with(0)
+                .apply(op)
+                .map(|(state, _)| state)
+                .map_err(|(state, _)| state)
+        };
+        assert_eq!(StateKind::Ready, apply(Operation::Ready).unwrap().kind());
+        assert_eq!(
+            StateKind::Stored,
+            apply(Operation::Store).unwrap_err().kind()
+        );
+        assert_eq!(
+            StateKind::Stored,
+            apply(Operation::Pause).unwrap_err().kind()
+        );
+        assert_eq!(
+            StateKind::Stored,
+            apply(Operation::Resume).unwrap_err().kind()
+        );
+        assert_eq!(
+            StateKind::Stored,
+            apply(Operation::Recover).unwrap_err().kind()
+        );
+        assert_eq!(
+            StateKind::Stored,
+            apply(Operation::Rearm).unwrap_err().kind()
+        );
+        assert_eq!(StateKind::Stored, apply(Operation::Reset).unwrap().kind());
+    }
+
+    #[test]
+    fn apply_from_ready() {
+        let apply = |op| {
+            State::ready_with(1)
+                .apply(op)
+                .map(|(state, _)| state)
+                .map_err(|(state, _)| state)
+        };
+        assert_eq!(
+            StateKind::Ready,
+            apply(Operation::Ready).unwrap_err().kind()
+        );
+        assert_eq!(StateKind::Stored, apply(Operation::Store).unwrap().kind());
+        assert_eq!(StateKind::Paused, apply(Operation::Pause).unwrap().kind());
+        assert_eq!(
+            StateKind::Ready,
+            apply(Operation::Resume).unwrap_err().kind()
+        );
+        assert_eq!(
+            StateKind::Ready,
+            apply(Operation::Recover).unwrap_err().kind()
+        );
+        assert_eq!(StateKind::Ready, apply(Operation::Rearm).unwrap().kind());
+        assert_eq!(StateKind::Stored, apply(Operation::Reset).unwrap().kind());
+    }
+
+    #[test]
+    fn apply_from_paused() {
+        let apply = |op| {
+            State::ready_with(1)
+                .pause()
+                .unwrap()
+                .apply(op)
+                .map(|(state, _)| state)
+                .map_err(|(state, _)| state)
+        };
+        assert_eq!(
+            StateKind::Paused,
+            apply(Operation::Ready).unwrap_err().kind()
+        );
+        assert_eq!(StateKind::Stored, apply(Operation::Store).unwrap().kind());
+        assert_eq!(
+            StateKind::Paused,
+            apply(Operation::Pause).unwrap_err().kind()
+        );
+        assert_eq!(StateKind::Ready, apply(Operation::Resume).unwrap().kind());
+        assert_eq!(
+            StateKind::Paused,
+            apply(Operation::Recover).unwrap_err().kind()
+        );
+        assert_eq!(
+            StateKind::Paused,
+            apply(Operation::Rearm).unwrap_err().kind()
+        );
+        assert_eq!(StateKind::Stored, apply(Operation::Reset).unwrap().kind());
+    }
+
+    #[test]
+    fn apply_from_error() {
+        let apply = |op| {
+            State::stored_with(0)
+                .error("test".to_string())
+                .apply(op)
+                .map(|(state, _)| state)
+                .map_err(|(state, _)| state)
+        };
+        assert_eq!(
+            StateKind::Error,
+            apply(Operation::Ready).unwrap_err().kind()
+        );
+        assert_eq!(
+            StateKind::Error,
+            apply(Operation::Store).unwrap_err().kind()
+        );
+        assert_eq!(
+            StateKind::Error,
+            apply(Operation::Pause).unwrap_err().kind()
+        );
+        assert_eq!(
+            StateKind::Error,
+            apply(Operation::Resume).unwrap_err().kind()
+        );
+        assert_eq!(StateKind::Stored, apply(Operation::Recover).unwrap().kind());
+        assert_eq!(
+            StateKind::Error,
+            apply(Operation::Rearm).unwrap_err().kind()
+        );
+        assert_eq!(StateKind::Stored, apply(Operation::Reset).unwrap().kind());
+    }
+
+    #[test]
+    fn every_operation_has_a_description() {
+        for op in Operation::ALL {
+            let description = op.description();
+            assert!(description.ends_with('.'), "{op:?}: {description}");
+        }
+        assert!(Operation::Ready.description().contains("ready count"));
+    }
+
+    #[test]
+    fn apply_reports_transition() {
+        let (state, report) = State::stored_with(0)
+            .apply(Operation::Ready)
+            .expect("should be able to transition from stored to ready");
+        assert_eq!(
+            TransitionReport {
+                from: "stored",
+                to: "ready",
+                op: Operation::Ready,
+                ready_count_before: 0,
+                ready_count_after: 1,
+            },
+            report
+        );
+        assert_eq!(StateKind::Ready, state.kind());
+    }
+}
@@ -0,0 +1,52 @@
+/// Controls what happens when entering the ready state would increment the ready count beyond
+/// `u64::MAX`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum OverflowMode {
+    /// Clamps the ready count at `u64::MAX`.
+    #[default]
+    Saturate,
+    /// Wraps the ready count around, starting over from 0.
+    Wrap,
+    /// Rejects the transition into the ready state.
+    Error,
+}
+
+impl OverflowMode {
+    /// Increments `count` by `step` according to this mode. Returns `None` when the count would
+    /// overflow and the mode is `Error`.
+    pub fn increment(self, count: u64, step: u64) -> Option<u64> {
+        match self {
+            OverflowMode::Saturate => Some(count.saturating_add(step)),
+            OverflowMode::Wrap => Some(count.wrapping_add(step)),
+            OverflowMode::Error => count.checked_add(step),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increment_at_max() {
+        assert_eq!(
+            Some(u64::MAX),
+            OverflowMode::Saturate.increment(u64::MAX, 1)
+        );
+        assert_eq!(Some(0), OverflowMode::Wrap.increment(u64::MAX, 1));
+        assert_eq!(None, OverflowMode::Error.increment(u64::MAX, 1));
+    }
+
+    #[test]
+    fn increment_below_max() {
+        for mode in [
+            OverflowMode::Saturate,
+            OverflowMode::Wrap,
+            OverflowMode::Error,
+        ] {
+            assert_eq!(Some(3), mode.increment(1, 2), "{mode:?}");
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use crate::*;
+pub use internal::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Span;
+
+mod internal {
+    use super::*;
+
+    #[derive(Debug)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    pub struct PausedState {
+        ready_count: u64,
+        transition_count: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        overflowed: bool,
+        #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+        clock: Arc<dyn Clock>,
+        #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+        logger: Arc<dyn StateLogger>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        transform: Option<Arc<dyn TransitionTransform>>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        config: Config,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        last_request_id: Option<u64>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        previous: Option<&'static str>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        time_in_previous: Duration,
+        #[cfg_attr(feature = "serde", serde(skip, default = "Timestamp::now"))]
+        paused_start: Timestamp,
+        #[cfg_attr(feature = "serde", serde(skip, default = "Span::none"))]
+        span: Span,
+    }
+
+    impl PausedState {
+        /// Panics if the inputs are invalid, see `try_enter`.
+        pub fn enter(inputs: PausedStateInputs) -> Self {
+            Self::try_enter(inputs).expect("the inputs should be valid")
+        }
+
+        /// Like `enter`, but rejects invalid inputs instead of panicking.
+        pub fn try_enter(inputs: PausedStateInputs) -> Result<Self, EnterError> {
+            inputs.config.validate()?;
+
+            let PausedStateInputs {
+                ready_count,
+                transition_count,
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                from,
+                time_in_previous,
+            } = inputs;
+
+            let CarriedData {
+                ready_count,
+                last_request_id,
+            } = CarriedData {
+                ready_count,
+                last_request_id,
+            }
+            .transformed(transform.as_deref(), from, StateKind::Paused, &config);
+
+            let state = Self {
+                ready_count,
+                transition_count,
+                overflowed,
+                paused_start: clock.timestamp(),
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                previous: from,
+                time_in_previous,
+                span: tracing::info_span!(
+                    "paused",
+                    ready_count,
+                    last_request_id = tracing::field::Empty
+                ),
+            };
+            state.log_entered();
+            Ok(state)
+        }
+
+        fn log_entered(&self) {
+            self.span
+                .in_scope(|| self.logger.state_entered("paused", self.ready_count));
+        }
+
+        pub fn exit(self) -> PausedStateOutputs {
+            let Self {
+                ready_count,
+                transition_count,
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                previous: _,
+                time_in_previous: _,
+                paused_start,
+                span,
+            } = self;
+
+            let time_in_state = clock.timestamp().saturating_duration_since(paused_start);
+            span.record("last_request_id", last_request_id);
+            span.in_scope(|| logger.state_exited("paused", time_in_state, ready_count));
+
+            PausedStateOutputs {
+                ready_count,
+                transition_count: transition_count.saturating_add(1),
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                time_in_state,
+            }
+        }
+
+        pub fn ready_count(&self) -> u64 {
+            self.ready_count
+        }
+
+        pub fn transition_count(&self) -> u64 {
+            self.transition_count
+        }
+
+        pub fn overflowed(&self) -> bool {
+            self.overflowed
+        }
+
+        pub fn clock(&self) -> &Arc<dyn Clock> {
+            &self.clock
+        }
+
+        /// The configuration, which is carried forward through all transitions.
+        pub fn config(&self) -> &Config {
+            &self.config
+        }
+
+        /// The id of the last request that transitioned the state machine, if any.
+        pub fn last_request_id(&self) -> Option<u64> {
+            self.last_request_id
+        }
+
+        /// Tags the state with the id of the request that is about to transition it, so the id
+        /// shows up in the logs of `exit`.
+        pub(crate) fn set_last_request_id(&mut self, last_request_id: Option<u64>) {
+            self.last_request_id = last_request_id;
+        }
+
+        /// The name of the state we transitioned from to get here, or `None` if the state machine
+        /// started in this state.
+        pub fn previous(&self) -> Option<&'static str> {
+            self.previous
+        }
+
+        /// How long we spent in the previous state, or zero if there is none.
+        pub fn time_in_previous(&self) -> Duration {
+            self.time_in_previous
+        }
+
+        /// The span that is open while we are in the paused state. Enter it to associate logs
+        /// with this state.
+        pub fn span(&self) -> &Span {
+            &self.span
+        }
+
+        /// When we entered the paused state.
+        pub fn entered_at(&self) -> Timestamp {
+            self.paused_start
+        }
+
+        /// How long we have been in the paused state so far.
+        pub fn elapsed(&self) -> Duration {
+            self.clock
+                .timestamp()
+                .saturating_duration_since(self.paused_start)
+        }
+    }
+}
+
+/// This defines the inputs required to enter the PausedState.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PausedStateInputs {
+    pub ready_count: u64,
+    pub transition_count: u64,
+    /// Whether the ready count has ever overflowed, see `State::has_overflowed`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overflowed: bool,
+    #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+    pub clock: Arc<dyn Clock>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+    pub logger: Arc<dyn StateLogger>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub transform: Option<Arc<dyn TransitionTransform>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub config: Config,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_request_id: Option<u64>,
+    /// The name of the state we are transitioning from, if any.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub from: Option<&'static str>,
+    /// How long we spent in the state we are transitioning from.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub time_in_previous: Duration,
+}
+
+/// This defines the outputs provided when leaving the PausedState.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PausedStateOutputs {
+    pub ready_count: u64,
+    pub transition_count: u64,
+    /// Whether the ready count has ever overflowed, see `State::has_overflowed`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overflowed: bool,
+    #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+    pub clock: Arc<dyn Clock>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+    pub logger: Arc<dyn StateLogger>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub transform: Option<Arc<dyn TransitionTransform>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub config: Config,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_request_id: Option<u64>,
+    /// How long we spent in the state that was exited.
+    pub time_in_state: Duration,
+}
+
+// The paused state has two transitions leading to different states, so each gets its own
+// transition result type.
+impl_state_transition_result! {
+    pub enum PausedStateResumeTransitionResult {
+        Ready(ReadyState),
+    }
+}
+
+impl_state_transition_result! {
+    pub enum PausedStateStoreTransitionResult {
+        Stored(StoredState),
+    }
+}
+
+impl PausedState {
+    /// Resuming enters the ready state, which increments the ready count. Like
+    /// `StoredState::ready_checked`, this hands back the state without calling `exit` when the
+    /// increment would exceed `max_ready_count` or overflow in `OverflowMode::Error`.
+    pub fn resume(self) -> Result<PausedStateResumeTransitionResult, Self> {
+        if self.config().next_ready_count(self.ready_count()).is_none() {
+            return Err(self);
+        }
+
+        let PausedStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
+
+        Ok(PausedStateResumeTransitionResult::Ready(ReadyState::enter(
+            ReadyStateInputs {
+                ready_count,
+                transition_count,
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                from: Some("paused"),
+                time_in_previous: time_in_state,
+                now: None,
+            },
+        )))
+    }
+
+    pub fn store(self) -> PausedStateStoreTransitionResult {
+        let PausedStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
+
+        PausedStateStoreTransitionResult::Stored(StoredState::enter(StoredStateInputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            from: Some("paused"),
+            time_in_previous: time_in_state,
+            now: None,
+        }))
+    }
+
+    pub fn error(self, reason: String, recoverable: bool) -> ErrorState {
+        let PausedStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
+
+        ErrorState::enter(ErrorStateInputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            from: Some("paused"),
+            time_in_previous: time_in_state,
+            reason,
+            recoverable,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paused_state() -> PausedState {
+        let state = ReadyState::enter(ReadyStateInputs::new(0));
+        let ReadyStatePauseTransitionResult::Paused(state) = state.pause();
+        assert_eq!(
+            1,
+            state.ready_count(),
+            "pausing should preserve the ready count"
+        );
+        state
+    }
+
+    #[test]
+    fn ready_paused_ready() {
+        let PausedStateResumeTransitionResult::Ready(state) = paused_state()
+            .resume()
+            .expect("should be able to transition from paused to ready");
+        assert_eq!(
+            2,
+            state.ready_count(),
+            "resuming enters the ready state which increments the ready count"
+        );
+    }
+
+    #[test]
+    fn ready_paused_stored() {
+        let PausedStateStoreTransitionResult::Stored(state) = paused_state().store();
+        assert_eq!(1, state.ready_count());
+    }
+
+    #[test]
+    fn resume_respects_max_ready_count() {
+        let state = StoredState::enter(StoredStateInputs {
+            config: Config {
+                max_ready_count: Some(1),
+                ..Config::default()
+            },
+            ..StoredStateInputs::new(0)
+        });
+        let StoredStateTransitionResult::Ready(state) = state.ready();
+        let ReadyStatePauseTransitionResult::Paused(state) = state.pause();
+        let state = state
+            .resume()
+            .expect_err("resuming should not exceed the maximum");
+        assert_eq!(
+            1,
+            state.ready_count(),
+            "the rejected state should be preserved"
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use crate::*;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+impl State {
+    /// Writes the name and ready count of the state to a file. Timers are not persisted.
+    pub fn save_to_path(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let StateSnapshot {
+            name, ready_count, ..
+        } = self.snapshot();
+        fs::write(path, format!("{name} {ready_count}\n"))
+    }
+
+    /// Reads a state written by `save_to_path`. The ready count is restored exactly and the timers
+    /// restart. An error state is restored as a recoverable error because the reason is not
+    /// persisted.
+    pub fn load_from_path(path: impl AsRef<Path>) -> io::Result<State> {
+        let contents = fs::read_to_string(path)?;
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let mut parts = contents.split_whitespace();
+        let (Some(name), Some(ready_count), None) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid(format!("malformed state file: {contents:?}")));
+        };
+        let ready_count = ready_count
+            .parse()
+            .map_err(|error| invalid(format!("invalid ready count {ready_count:?}: {error}")))?;
+
+        restore(name, ready_count).ok_or_else(|| invalid(format!("unknown state {name:?}")))
+    }
+}
+
+/// Reconstructs the state with the given name with exactly the given ready count.
+pub(crate) fn restore(name: &str, ready_count: u64) -> Option<State> {
+    Some(match StateKind::from_name(name)? {
+        StateKind::Uninitialized => State::default(),
+        StateKind::Stored => State::stored_with(ready_count),
+        StateKind::Ready => State::ready_with(ready_count),
+        StateKind::Paused => State::Paused(PausedState::enter(PausedStateInputs {
+            ready_count,
+            transition_count: 0,
+            overflowed: false,
+            clock: system_clock(),
+            logger: tracing_logger(),
+            transform: None,
+            config: Config::default(),
+            from: None,
+            last_request_id: None,
+            time_in_previous: Duration::ZERO,
+        })),
+        StateKind::Error => State::Error(ErrorState::enter(ErrorStateInputs {
+            ready_count,
+            transition_count: 0,
+            overflowed: false,
+            clock: system_clock(),
+            logger: tracing_logger(),
+            transform: None,
+            config: Config::default(),
+            from: None,
+            last_request_id: None,
+            time_in_previous: Duration::ZERO,
+            reason: "restored from a previous run".to_string(),
+            recoverable: true,
+        })),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+
+        State::ready_with(4).save_to_path(&path).unwrap();
+        let state = State::load_from_path(&path).unwrap();
+        assert_eq!(StateKind::Ready, state.kind());
+        assert_eq!(4, state.ready_count());
+    }
+
+    #[test]
+    fn load_unknown_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+
+        fs::write(&path, "flying 4\n").unwrap();
+        let error = State::load_from_path(&path).expect_err("flying is not a state");
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        fs::write(&path, "ready four\n").unwrap();
+        let error = State::load_from_path(&path).expect_err("four is not a number");
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+}
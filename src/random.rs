@@ -0,0 +1,64 @@
+use crate::*;
+use rand::Rng;
+
+impl State {
+    /// Applies one of the operations listed in `available_operations`, picked uniformly at random,
+    /// which is useful for simulating load. Operations that require more than their name, like
+    /// `initialize`, are never picked. The state is handed back unchanged when there is nothing to
+    /// pick from.
+    pub fn step_random(self, rng: &mut impl Rng) -> State {
+        let ops: Vec<Operation> = self
+            .available_operations()
+            .iter()
+            .filter_map(|name| name.parse().ok())
+            .filter(|&op| self.can(op))
+            .collect();
+        if ops.is_empty() {
+            return self;
+        }
+
+        let op = ops[rng.random_range(0..ops.len())];
+        match self.apply(op) {
+            Ok((state, _)) => state,
+            Err((state, _)) => state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn step_random() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut state = State::default_with(Config::default());
+        let mut ready_count = state.ready_count();
+        for _ in 0..1000 {
+            state = state.step_random(&mut rng);
+            assert!(state.ready_count() >= ready_count);
+            ready_count = state.ready_count();
+        }
+        assert!(ready_count > 0, "the machine should have been driven");
+    }
+
+    #[test]
+    fn step_random_rearms() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let rearmed = (0..100)
+            .map(|_| State::ready_with(1).step_random(&mut rng))
+            .any(|state| state.kind() == StateKind::Ready && state.ready_count() == 2);
+        assert!(rearmed, "rearm should be picked from the ready state");
+    }
+
+    #[test]
+    fn step_random_without_operations() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let state = State::stored_with(0)
+            .error_with("unrecoverable".to_string(), false)
+            .step_random(&mut rng);
+        assert_eq!("error", state.name());
+    }
+}
@@ -1,64 +1,714 @@
+use crate::exit_guard::ExitGuard;
 use crate::*;
 pub use internal::*;
-use std::time::Instant;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Span;
 
 // Helps enforce the usage of `exit` by defining transitions outside of this module.
 mod internal {
     pub use super::*;
 
-    #[derive(Debug)]
-    pub struct ReadyState {
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    pub struct ReadyState<P = ()> {
         ready_count: u64,
-        ready_start: Instant,
+        transition_count: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        overflowed: bool,
+        #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+        clock: Arc<dyn Clock>,
+        #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+        logger: Arc<dyn StateLogger>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        transform: Option<Arc<dyn TransitionTransform>>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        config: Config,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        last_request_id: Option<u64>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        previous: Option<&'static str>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        time_in_previous: Duration,
+        #[cfg_attr(feature = "serde", serde(skip, default = "Timestamp::now"))]
+        ready_start: Timestamp,
+        #[cfg_attr(feature = "serde", serde(skip, default = "ready_exit_guard"))]
+        exit_guard: ExitGuard,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        payload: P,
+        #[cfg_attr(feature = "serde", serde(skip, default = "Span::none"))]
+        span: Span,
     }
 
     impl ReadyState {
+        /// Panics if the inputs are invalid, see `try_enter`.
+        ///
+        /// The ready count is incremented according to `config.overflow_mode`, which saturates at
+        /// `u64::MAX` by default. This is the same in debug and release builds: the increment never
+        /// panics and only wraps when `OverflowMode::Wrap` is chosen explicitly.
         pub fn enter(inputs: ReadyStateInputs) -> Self {
-            let ReadyStateInputs { ready_count } = inputs;
+            Self::try_enter(inputs).expect("the inputs should be valid")
+        }
+
+        /// Like `enter`, but rejects invalid inputs instead of panicking.
+        pub fn try_enter(inputs: ReadyStateInputs) -> Result<Self, EnterError> {
+            Self::try_enter_with_payload(inputs, ())
+        }
+
+        /// Reconstructs a ready state with exactly the given ready count, for example when resuming
+        /// from persisted data. Unlike `enter`, this does not increment the ready count.
+        pub fn restore(ready_count: u64) -> Self {
+            let clock = system_clock();
+            let ready_start = clock.timestamp();
+
+            let state = Self {
+                ready_count,
+                transition_count: 0,
+                overflowed: false,
+                ready_start,
+                exit_guard: ExitGuard::new("ready", Arc::clone(&clock), ready_start),
+                clock,
+                logger: tracing_logger(),
+                transform: None,
+                config: Config::default(),
+                previous: None,
+                payload: (),
+                last_request_id: None,
+                time_in_previous: Duration::ZERO,
+                span: tracing::info_span!(
+                    "ready",
+                    ready_count,
+                    last_request_id = tracing::field::Empty
+                ),
+            };
+            state.log_entered();
+            state
+        }
+    }
+
+    impl<P> ReadyState<P> {
+        /// Like `enter`, but stores `payload` in the state, see `payload`.
+        pub fn enter_with_payload(inputs: ReadyStateInputs, payload: P) -> Self {
+            Self::try_enter_with_payload(inputs, payload).expect("the inputs should be valid")
+        }
+
+        /// Like `enter_with_payload`, but rejects invalid inputs instead of panicking.
+        pub fn try_enter_with_payload(
+            inputs: ReadyStateInputs,
+            payload: P,
+        ) -> Result<Self, EnterError> {
+            inputs.config.validate()?;
+
+            let ReadyStateInputs {
+                ready_count,
+                transition_count,
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                from,
+                time_in_previous,
+                now,
+            } = inputs;
+
+            // Entering can not fail, so the ready count is left as is when it would overflow in
+            // `OverflowMode::Error`. Transitions check for this before leaving their state.
+            let overflowed = overflowed
+                || (config.overflow_mode != OverflowMode::Error
+                    && ready_count.checked_add(config.step).is_none());
+            let ready_count = config
+                .overflow_mode
+                .increment(ready_count, config.step)
+                .unwrap_or(ready_count);
 
-            Self {
-                ready_count: ready_count + 1,
-                ready_start: Instant::now(),
+            let CarriedData {
+                ready_count,
+                last_request_id,
+            } = CarriedData {
+                ready_count,
+                last_request_id,
             }
+            .transformed(transform.as_deref(), from, StateKind::Ready, &config);
+
+            let ready_start = now.unwrap_or_else(|| clock.timestamp());
+            let state = Self {
+                ready_count,
+                transition_count,
+                overflowed,
+                ready_start,
+                exit_guard: ExitGuard::new("ready", Arc::clone(&clock), ready_start),
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                previous: from,
+                payload,
+                time_in_previous,
+                span: tracing::info_span!(
+                    "ready",
+                    ready_count,
+                    last_request_id = tracing::field::Empty
+                ),
+            };
+            state.log_entered();
+            Ok(state)
+        }
+
+        fn log_entered(&self) {
+            self.span
+                .in_scope(|| self.logger.state_entered("ready", self.ready_count));
         }
 
         pub fn exit(self) -> ReadyStateOutputs {
             let Self {
                 ready_count,
+                transition_count,
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                previous: _,
+                time_in_previous: _,
                 ready_start,
+                span,
+                exit_guard,
+                payload: _,
             } = self;
+            exit_guard.disarm();
 
-            tracing::info!("Spent {:?} in ready state.", ready_start.elapsed());
+            let time_in_state = clock.timestamp().saturating_duration_since(ready_start);
+            span.record("last_request_id", last_request_id);
+            span.in_scope(|| logger.state_exited("ready", time_in_state, ready_count));
 
-            ReadyStateOutputs { ready_count }
+            ReadyStateOutputs {
+                ready_count,
+                transition_count: transition_count.saturating_add(1),
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                time_in_state,
+            }
         }
 
         pub fn ready_count(&self) -> u64 {
             self.ready_count
         }
+
+        pub fn transition_count(&self) -> u64 {
+            self.transition_count
+        }
+
+        pub fn overflowed(&self) -> bool {
+            self.overflowed
+        }
+
+        pub fn clock(&self) -> &Arc<dyn Clock> {
+            &self.clock
+        }
+
+        /// The configuration, which is carried forward through all transitions.
+        pub fn config(&self) -> &Config {
+            &self.config
+        }
+
+        /// The id of the last request that transitioned the state machine, if any.
+        pub fn last_request_id(&self) -> Option<u64> {
+            self.last_request_id
+        }
+
+        /// Tags the state with the id of the request that is about to transition it, so the id
+        /// shows up in the logs of `exit`.
+        pub(crate) fn set_last_request_id(&mut self, last_request_id: Option<u64>) {
+            self.last_request_id = last_request_id;
+        }
+
+        /// The name of the state we transitioned from to get here, or `None` if the state machine
+        /// started in this state.
+        pub fn previous(&self) -> Option<&'static str> {
+            self.previous
+        }
+
+        /// How long we spent in the previous state, or zero if there is none.
+        pub fn time_in_previous(&self) -> Duration {
+            self.time_in_previous
+        }
+
+        /// The span that is open while we are in the ready state. Enter it to associate logs
+        /// with this state.
+        pub fn span(&self) -> &Span {
+            &self.span
+        }
+
+        /// When we entered the ready state.
+        pub fn entered_at(&self) -> Timestamp {
+            self.ready_start
+        }
+
+        /// How long we have been in the ready state so far.
+        pub fn elapsed(&self) -> Duration {
+            self.clock
+                .timestamp()
+                .saturating_duration_since(self.ready_start)
+        }
+
+        /// The data that was passed in when entering the ready state, see
+        /// `StoredState::ready_with_payload`. The payload is dropped when the state is exited and
+        /// is not serialized.
+        pub fn payload(&self) -> &P {
+            &self.payload
+        }
+    }
+
+    // The clock, logger, span and start time are left out so that the output does not depend on
+    // when it is printed.
+    impl<P: fmt::Debug> fmt::Debug for ReadyState<P> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("ReadyState")
+                .field("ready_count", &self.ready_count)
+                .field("transition_count", &self.transition_count)
+                .field("overflowed", &self.overflowed)
+                .field("config", &self.config)
+                .field("last_request_id", &self.last_request_id)
+                .field("previous", &self.previous)
+                .field("time_in_previous", &self.time_in_previous)
+                .field("payload", &self.payload)
+                .finish_non_exhaustive()
+        }
     }
 }
 
+#[cfg(feature = "serde")]
+fn ready_exit_guard() -> ExitGuard {
+    ExitGuard::new("ready", system_clock(), Timestamp::now())
+}
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ReadyStateInputs {
     pub ready_count: u64,
+    pub transition_count: u64,
+    /// Whether the ready count has ever overflowed, see `State::has_overflowed`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overflowed: bool,
+    #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+    pub clock: Arc<dyn Clock>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+    pub logger: Arc<dyn StateLogger>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub transform: Option<Arc<dyn TransitionTransform>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub config: Config,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_request_id: Option<u64>,
+    /// The name of the state we are transitioning from, if any.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub from: Option<&'static str>,
+    /// How long we spent in the state we are transitioning from.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub time_in_previous: Duration,
+    /// When the state is entered, or `None` to ask the clock. Tests can pass a fixed instant to
+    /// get deterministic timestamps.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub now: Option<Timestamp>,
+}
+
+impl ReadyStateInputs {
+    /// Creates inputs with the default configuration for a state machine that has not made any
+    /// transitions yet.
+    pub fn new(ready_count: u64) -> Self {
+        Self {
+            ready_count,
+            transition_count: 0,
+            overflowed: false,
+            clock: system_clock(),
+            logger: tracing_logger(),
+            transform: None,
+            config: Config::default(),
+            from: None,
+            last_request_id: None,
+            time_in_previous: Duration::ZERO,
+            now: None,
+        }
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ReadyStateOutputs {
     pub ready_count: u64,
+    pub transition_count: u64,
+    /// Whether the ready count has ever overflowed, see `State::has_overflowed`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overflowed: bool,
+    #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+    pub clock: Arc<dyn Clock>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+    pub logger: Arc<dyn StateLogger>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub transform: Option<Arc<dyn TransitionTransform>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub config: Config,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_request_id: Option<u64>,
+    /// How long we spent in the state that was exited.
+    pub time_in_state: Duration,
 }
 
+// Polling the timeout either stores the state or keeps it ready.
 impl_state_transition_result! {
     pub enum ReadyStateTransitionResult {
         Stored(StoredState),
+        Ready(ReadyState),
     }
 }
 
+// The other transitions each lead to a single state, so they get their own transition result types
+// that keep that known to the caller.
+impl_state_transition_result! {
+    pub enum ReadyStateStoreTransitionResult {
+        Stored(StoredState),
+    }
+}
+
+impl_state_transition_result! {
+    pub enum ReadyStatePauseTransitionResult {
+        Paused(PausedState),
+    }
+}
+
+impl_state_transition_result! {
+    pub enum ReadyStateRearmTransitionResult {
+        Ready(ReadyState),
+    }
+}
+
+impl_transition_result_widening!(ReadyStateStoreTransitionResult => ReadyStateTransitionResult {
+    Stored,
+});
+
 impl ReadyState {
-    pub fn store(self) -> ReadyStateTransitionResult {
-        let ReadyStateOutputs { ready_count } = self.exit();
+    /// Stores the state if it has been idle for at least `auto_store_after`, otherwise stays ready.
+    pub fn poll_timeout(self) -> ReadyStateTransitionResult {
+        match self.config().auto_store_after {
+            Some(after) if self.elapsed() >= after => self.store().into(),
+            _ => ReadyStateTransitionResult::Ready(self),
+        }
+    }
+
+    /// Exits and enters the ready state again, which increments the ready count and restarts the
+    /// timer as if we came from another state. Like `StoredState::ready`, this does not check the
+    /// configured `max_ready_count`.
+    pub fn rearm(self) -> ReadyStateRearmTransitionResult {
+        let ReadyStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
+
+        ReadyStateRearmTransitionResult::Ready(ReadyState::enter(ReadyStateInputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            from: Some("ready"),
+            time_in_previous: time_in_state,
+            now: None,
+        }))
+    }
+}
+
+impl<P> ReadyState<P> {
+    pub fn store(self) -> ReadyStateStoreTransitionResult {
+        let ReadyStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
+
+        ReadyStateStoreTransitionResult::Stored(StoredState::enter(StoredStateInputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            from: Some("ready"),
+            time_in_previous: time_in_state,
+            now: None,
+        }))
+    }
+
+    pub fn pause(self) -> ReadyStatePauseTransitionResult {
+        let ReadyStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
+
+        ReadyStatePauseTransitionResult::Paused(PausedState::enter(PausedStateInputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            from: Some("ready"),
+            time_in_previous: time_in_state,
+        }))
+    }
+
+    pub fn error(self, reason: String, recoverable: bool) -> ErrorState {
+        let ReadyStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
+
+        ErrorState::enter(ErrorStateInputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            from: Some("ready"),
+            time_in_previous: time_in_state,
+            reason,
+            recoverable,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enter_increments_by_step() {
+        let state = ReadyState::enter(ReadyStateInputs {
+            config: Config {
+                step: 5,
+                ..Config::default()
+            },
+            ..ReadyStateInputs::new(2)
+        });
+        assert_eq!(7, state.ready_count());
+        let state = ReadyState::enter(ReadyStateInputs::new(2));
+        assert_eq!(3, state.ready_count());
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn elapsed_follows_clock() {
+        let clock = MockClock::new();
+        let state = ReadyState::enter(ReadyStateInputs {
+            clock: Arc::new(clock.clone()),
+            ..ReadyStateInputs::new(0)
+        });
+        assert_eq!(Duration::ZERO, state.elapsed());
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(Duration::from_millis(1500), state.elapsed());
+    }
+
+    fn ready_with_timeout(clock: &MockClock, auto_store_after: Option<Duration>) -> ReadyState {
+        ReadyState::enter(ReadyStateInputs {
+            clock: Arc::new(clock.clone()),
+            logger: tracing_logger(),
+            transform: None,
+            config: Config {
+                auto_store_after,
+                ..Config::default()
+            },
+            ..ReadyStateInputs::new(0)
+        })
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn poll_timeout_stores_when_idle() {
+        let clock = MockClock::new();
+        let state = ready_with_timeout(&clock, Some(Duration::from_secs(10)));
+        let ReadyStateTransitionResult::Ready(state) = state.poll_timeout() else {
+            panic!("should stay ready before the timeout");
+        };
+        clock.advance(Duration::from_secs(10));
+        let ReadyStateTransitionResult::Stored(state) = state.poll_timeout() else {
+            panic!("should store once the timeout has passed");
+        };
+        assert_eq!(1, state.ready_count());
+        let StoredStateTransitionResult::Ready(state) = state.ready();
+        assert_eq!(
+            Some(Duration::from_secs(10)),
+            state.config().auto_store_after,
+            "the timeout should be carried forward"
+        );
+    }
+
+    #[test]
+    fn poll_timeout_without_timeout() {
+        let clock = MockClock::new();
+        let state = ready_with_timeout(&clock, None);
+        clock.advance(Duration::from_secs(3600));
+        assert!(matches!(
+            state.poll_timeout(),
+            ReadyStateTransitionResult::Ready(_)
+        ));
+    }
+
+    #[test]
+    fn restore_does_not_increment() {
+        let state = ReadyState::restore(10);
+        assert_eq!(10, state.ready_count());
+        assert_eq!(0, state.transition_count());
+    }
+
+    #[test]
+    fn enter_saturates() {
+        let state = ReadyState::enter(ReadyStateInputs {
+            config: Config {
+                step: 5,
+                ..Config::default()
+            },
+            ..ReadyStateInputs::new(u64::MAX - 1)
+        });
+        assert_eq!(u64::MAX, state.ready_count());
+    }
+
+    #[test]
+    fn ready_count_stays_at_max() {
+        let state = State::Ready(ReadyState::restore(u64::MAX))
+            .store()
+            .and_then(State::ready)
+            .expect("should be able to transition from stored to ready");
+        assert_eq!("ready", state.name());
+        assert_eq!(u64::MAX, state.ready_count());
+    }
+
+    #[test]
+    fn saturating_sets_overflowed() {
+        let state = State::Ready(ReadyState::restore(u64::MAX));
+        assert!(!state.has_overflowed());
+        let state = state.store().expect("should be able to store");
+        assert!(!state.has_overflowed(), "storing does not increment");
+        let state = state
+            .ready()
+            .and_then(State::store)
+            .expect("should be able to ready and store");
+        assert!(state.has_overflowed(), "the flag should stick");
+        assert_eq!(u64::MAX, state.ready_count());
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn rearm_increments_and_restarts_timer() {
+        let clock = MockClock::new();
+        let state = ReadyState::enter(ReadyStateInputs {
+            clock: Arc::new(clock.clone()),
+            ..ReadyStateInputs::new(0)
+        });
+        let entered_at = state.entered_at();
+        clock.advance(Duration::from_secs(1));
+
+        let ReadyStateRearmTransitionResult::Ready(state) = state.rearm();
+        assert_eq!(2, state.ready_count());
+        assert_eq!(entered_at + Duration::from_secs(1), state.entered_at());
+        assert_eq!(Duration::ZERO, state.elapsed());
+        assert_eq!(Some("ready"), state.previous());
+        assert_eq!(Duration::from_secs(1), state.time_in_previous());
+
+        let state = State::Ready(state)
+            .rearm()
+            .expect("should be able to rearm the ready state");
+        assert_eq!(3, state.ready_count());
+        State::stored_with(0)
+            .rearm()
+            .expect_err("can only rearm the ready state");
+    }
+
+    #[test]
+    fn ready_with_string_payload() {
+        let state = StoredState::enter(StoredStateInputs::new(0))
+            .ready_with_payload(String::from("request body"));
+        assert_eq!("request body", state.payload());
+        assert_eq!(1, state.ready_count());
+        assert!(format!("{state:?}").contains("payload: \"request body\""));
+
+        let ReadyStateStoreTransitionResult::Stored(state) = state.store();
+        assert_eq!(1, state.ready_count());
+    }
+
+    #[test]
+    fn payload_defaults_to_unit() {
+        let state: ReadyState = ReadyState::enter(ReadyStateInputs::new(0));
+        let _: &() = state.payload();
+        assert_eq!("ready", State::from(state).name());
+    }
+
+    #[test]
+    fn debug_omits_timer() {
+        let debug = format!("{:?}", ReadyState::restore(1));
+        assert!(debug.starts_with("ReadyState {"), "{debug}");
+        assert!(debug.contains("ready_count: 1"), "{debug}");
+        assert!(!debug.contains("Instant"), "{debug}");
+    }
+
+    #[test]
+    fn drop_without_exit_warns() {
+        use crate::capture::capture;
+
+        let (_, records) = capture(|| drop(ReadyState::restore(1)));
+        let warning = records
+            .iter()
+            .find(|record| record.level() == Some(tracing::Level::WARN))
+            .expect("dropping the state should log a warning");
+        assert_eq!(Some("\"ready\""), warning.field("state"));
 
-        ReadyStateTransitionResult::Stored(StoredState::enter(StoredStateInputs { ready_count }))
+        let (_, records) = capture(|| ReadyState::restore(1).exit());
+        assert!(records
+            .iter()
+            .all(|record| record.level() != Some(tracing::Level::WARN)));
     }
 }
@@ -0,0 +1,120 @@
+use crate::*;
+use std::fmt;
+
+/// Describes why an event log could not be replayed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The event names an operation that does not exist.
+    UnknownOperation(&'static str),
+    /// The operation of the event is not legal from the replayed state.
+    Illegal(TransitionError),
+    /// The operation led to a different state than the one recorded in the event.
+    StateMismatch {
+        expected: &'static str,
+        actual: &'static str,
+    },
+    /// The operation led to a different ready count than the one recorded in the event.
+    CountMismatch { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::UnknownOperation(op) => write!(f, "unknown operation {op:?}"),
+            ReplayError::Illegal(error) => write!(f, "{error}"),
+            ReplayError::StateMismatch { expected, actual } => {
+                write!(f, "expected to end up in {expected} but got {actual}")
+            }
+            ReplayError::CountMismatch { expected, actual } => {
+                write!(f, "expected a ready count of {expected} but got {actual}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl State {
+    /// Reconstructs the state that a `StateMachine` ended up in from the events it emitted,
+    /// starting from `State::default`. Every event is checked against the replayed transition.
+    /// The configuration is not part of the events, so `initialize` uses the default
+    /// configuration.
+    pub fn replay(events: &[TransitionEvent]) -> Result<State, ReplayError> {
+        let mut state = State::default();
+        for event in events {
+            let result = if event.op == "initialize" {
+                state.initialize(Config::default())
+            } else {
+                let op = event
+                    .op
+                    .parse()
+                    .map_err(|_| ReplayError::UnknownOperation(event.op))?;
+                state.apply(op).map(|(state, _)| state)
+            };
+            state = result.map_err(|(_, error)| ReplayError::Illegal(error))?;
+            if state.name() != event.to {
+                return Err(ReplayError::StateMismatch {
+                    expected: event.to,
+                    actual: state.name(),
+                });
+            }
+            if state.ready_count() != event.ready_count {
+                return Err(ReplayError::CountMismatch {
+                    expected: event.ready_count,
+                    actual: state.ready_count(),
+                });
+            }
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_events() -> Vec<TransitionEvent> {
+        let mut machine = StateMachine::default();
+        machine
+            .try_initialize(Config::default())
+            .expect("should be able to initialize");
+        machine
+            .try_ready()
+            .expect("should be able to transition from stored to ready");
+        machine
+            .try_pause()
+            .expect("should be able to transition from ready to paused");
+        machine
+            .try_resume()
+            .expect("should be able to transition from paused to ready");
+        machine.events().to_vec()
+    }
+
+    #[test]
+    fn replay_valid_log() {
+        let state = State::replay(&record_events()).expect("the log should replay");
+        assert_eq!(StateKind::Ready, state.kind());
+        assert_eq!(2, state.ready_count());
+    }
+
+    #[test]
+    fn replay_tampered_count() {
+        let mut events = record_events();
+        events[1].ready_count = 7;
+        assert_eq!(
+            Err(ReplayError::CountMismatch {
+                expected: 7,
+                actual: 1,
+            }),
+            State::replay(&events).map(|state| state.name())
+        );
+    }
+
+    #[test]
+    fn replay_illegal_transition() {
+        let mut events = record_events();
+        events.remove(0);
+        let error = State::replay(&events).expect_err("can not ready before initializing");
+        assert!(matches!(error, ReplayError::Illegal(_)), "{error}");
+    }
+}
@@ -0,0 +1,665 @@
+use crate::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+
+/// Identifies one of the state machines owned by `run`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MachineId(pub u64);
+
+impl fmt::Display for MachineId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// A request to operate on one of the state machines. The response describing the outcome is sent
+/// back over the included channel.
+pub enum Command {
+    Apply {
+        id: MachineId,
+        op: Operation,
+        respond: oneshot::Sender<String>,
+    },
+    /// Reports the current state without transitioning.
+    Status {
+        id: MachineId,
+        respond: oneshot::Sender<String>,
+    },
+    /// Lists the states that have been entered, one per line, with the time at which they were
+    /// entered relative to the first one.
+    History {
+        id: MachineId,
+        respond: oneshot::Sender<String>,
+    },
+    /// Reports the operations that are currently legal, see `State::legal_operations`.
+    Operations {
+        id: MachineId,
+        respond: oneshot::Sender<Vec<Operation>>,
+    },
+    /// Describes every operation and command, one per line. This does not refer to a machine.
+    Help { respond: oneshot::Sender<String> },
+}
+
+/// Like `TransitionObserver`, but awaited by the `run` loop after it has responded to the command
+/// that made the transition, so an observer can do I/O without blocking the transition itself.
+/// Implement it with `async fn on_transition`.
+pub trait AsyncTransitionObserver: Send + Sync {
+    fn on_transition(&self, report: TransitionReport) -> impl Future<Output = ()> + Send;
+}
+
+/// The object safe version of `AsyncTransitionObserver`, which boxes the future.
+trait DynAsyncTransitionObserver: Send + Sync {
+    fn on_transition(
+        &self,
+        report: TransitionReport,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+impl<T: AsyncTransitionObserver> DynAsyncTransitionObserver for T {
+    fn on_transition(
+        &self,
+        report: TransitionReport,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(AsyncTransitionObserver::on_transition(self, report))
+    }
+}
+
+/// The observers that `run_observed` notifies of every transition, in the order in which they
+/// were added.
+#[derive(Default)]
+pub struct AsyncObservers {
+    observers: Vec<Box<dyn DynAsyncTransitionObserver>>,
+}
+
+impl AsyncObservers {
+    pub fn add(&mut self, observer: impl AsyncTransitionObserver + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Awaits each observer in turn, so an observer sees the transitions in order.
+    async fn notify(&self, report: TransitionReport) {
+        for observer in &self.observers {
+            observer.on_transition(report).await;
+        }
+    }
+}
+
+/// The commands that do not transition, with descriptions for `Command::Help`.
+const COMMANDS: [(&str, &str); 3] = [
+    ("status", "Reports the current state."),
+    ("history", "Lists the states that have been entered."),
+    ("help", "Describes the operations and commands."),
+];
+
+/// Owns any number of state machines and applies commands to them until the sending half of the
+/// channel is dropped, after which the final state machines are returned. A state machine is
+/// created with `config` the first time a command refers to its id. A ready state with an
+/// `auto_store_after` duration is stored automatically once it has been idle for that long. The
+/// machines read the time through `TokioClock`, so pausing tokio's clock in tests also pauses
+/// theirs.
+///
+/// Sending on `shutdown` stops the loop without waiting for the channel to close. The current
+/// state of every machine is exited so its `exit` code runs, which leaves no machines to return.
+/// Dropping the sender of `shutdown` without sending does not stop the loop.
+pub async fn run(
+    config: Config,
+    rx: mpsc::Receiver<Command>,
+    shutdown: oneshot::Receiver<()>,
+) -> HashMap<MachineId, StateMachine> {
+    run_with_ready_count(config, 0, rx, shutdown).await
+}
+
+/// Like `run`, but the state machines start out stored with exactly the given ready count instead
+/// of 0.
+pub async fn run_with_ready_count(
+    config: Config,
+    ready_count: u64,
+    rx: mpsc::Receiver<Command>,
+    shutdown: oneshot::Receiver<()>,
+) -> HashMap<MachineId, StateMachine> {
+    run_observed(config, ready_count, AsyncObservers::default(), rx, shutdown).await
+}
+
+/// Like `run_with_ready_count`, but also notifies `observers` of every transition made through
+/// `Command::Apply`. Automatic transitions and shutting down are not reported.
+pub async fn run_observed(
+    config: Config,
+    ready_count: u64,
+    observers: AsyncObservers,
+    mut rx: mpsc::Receiver<Command>,
+    mut shutdown: oneshot::Receiver<()>,
+) -> HashMap<MachineId, StateMachine> {
+    let mut machines: HashMap<MachineId, StateMachine> = HashMap::new();
+    let mut shutdown_pending = true;
+    loop {
+        let next_auto_store = machines
+            .values()
+            .filter_map(|machine| machine.current().time_until_auto_store())
+            .min();
+        let auto_store = tokio::time::sleep(next_auto_store.unwrap_or_default());
+        let command = tokio::select! {
+            command = rx.recv() => command,
+            result = &mut shutdown, if shutdown_pending => {
+                if result.is_err() {
+                    shutdown_pending = false;
+                    continue;
+                }
+                for (id, machine) in machines.drain() {
+                    tracing::info!("Shutting down {id}.");
+                    machine.exit();
+                }
+                break;
+            }
+            _ = auto_store, if next_auto_store.is_some() => {
+                for (id, machine) in &mut machines {
+                    if machine.poll_timeout() {
+                        tracing::info!(
+                            "Automatically transitioned {id} to {}.",
+                            machine.current().name()
+                        );
+                    }
+                }
+                continue;
+            }
+        };
+        let Some(command) = command else {
+            break;
+        };
+        match command {
+            Command::Apply { id, op, respond } => {
+                let machine = machine(&mut machines, id, &config, ready_count);
+                let from = machine.current().name();
+                let ready_count_before = machine.current().ready_count();
+                let result = machine.try_apply(op).map(|()| machine.current().name());
+                let to = result.as_ref().ok().copied();
+                reply(respond, describe(result));
+                if let Some(to) = to {
+                    let report = TransitionReport {
+                        from,
+                        to,
+                        op,
+                        ready_count_before,
+                        ready_count_after: machine.current().ready_count(),
+                    };
+                    observers.notify(report).await;
+                }
+            }
+            Command::Status { id, respond } => {
+                let machine = machine(&mut machines, id, &config, ready_count);
+                reply(respond, format!("Current state is {}.", machine.current()));
+            }
+            Command::History { id, respond } => {
+                let machine = machine(&mut machines, id, &config, ready_count);
+                reply(respond, format_history(machine.history()));
+            }
+            Command::Operations { id, respond } => {
+                let machine = machine(&mut machines, id, &config, ready_count);
+                reply(respond, machine.current().legal_operations());
+            }
+            Command::Help { respond } => reply(respond, help()),
+        }
+    }
+    machines
+}
+
+/// The machine with the given id, which is created with `config` and `ready_count` if it does not
+/// exist yet.
+fn machine<'a>(
+    machines: &'a mut HashMap<MachineId, StateMachine>,
+    id: MachineId,
+    config: &Config,
+    ready_count: u64,
+) -> &'a mut StateMachine {
+    // Machines start out stored, the uninitialized state can not be left through an operation.
+    machines.entry(id).or_insert_with(|| {
+        StateMachine::new(State::Stored(StoredState::enter(StoredStateInputs {
+            clock: tokio_clock(),
+            config: config.clone(),
+            ..StoredStateInputs::new(ready_count)
+        })))
+    })
+}
+
+/// Sends the response to a command. The command has been carried out even if the requester stopped
+/// waiting for the response, but that is logged so requesters that leak their receivers are
+/// noticed.
+fn reply<T>(respond: oneshot::Sender<T>, response: T) {
+    if respond.send(response).is_err() {
+        tracing::warn!("response receiver dropped");
+    }
+}
+
+/// Applies the operations in order and collects the responses that `run` would send for them. A
+/// rejected operation leaves the state unchanged for the next one.
+pub fn process_batch(state: State, ops: Vec<Operation>) -> (State, Vec<String>) {
+    let mut responses = Vec::with_capacity(ops.len());
+    let state = ops.into_iter().fold(state, |state, op| {
+        let (state, response) = apply(state, op);
+        responses.push(response);
+        state
+    });
+    (state, responses)
+}
+
+fn apply(state: State, op: Operation) -> (State, String) {
+    match state.apply(op) {
+        Ok((state, _)) => {
+            let response = describe(Ok(state.name()));
+            (state, response)
+        }
+        Err((state, error)) => (state, describe(Err(error))),
+    }
+}
+
+/// Asks for one of `operations` or one of the commands that do not transition, like `status`.
+pub fn prompt(operations: &[Operation]) -> String {
+    let names: Vec<_> = operations
+        .iter()
+        .map(|op| op.name())
+        .chain(COMMANDS.map(|(name, _)| name))
+        .collect();
+    format!(
+        "Please enter an operation, optionally prefixed by a machine id: {}\n> ",
+        names.join(", ")
+    )
+}
+
+/// Lists every operation followed by the commands that do not transition, each with its
+/// description.
+fn help() -> String {
+    Operation::ALL
+        .map(|op| (op.name(), op.description()))
+        .into_iter()
+        .chain(COMMANDS)
+        .map(|(name, description)| format!("{name:<8} {description}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Describes the outcome of an operation given the name of the state that was entered.
+fn describe(result: Result<&'static str, TransitionError>) -> String {
+    match result {
+        Ok(name) => format!("Transitioned to {name}!"),
+        Err(error) => format!("Transition failed! {error}."),
+    }
+}
+
+fn format_history(history: &[(&'static str, Instant)]) -> String {
+    let Some(&(_, start)) = history.first() else {
+        return String::new();
+    };
+    history
+        .iter()
+        .enumerate()
+        .map(|(index, &(name, entered))| {
+            let offset = format_duration(entered.saturating_duration_since(start));
+            format!("{}. {name} at +{offset}", index + 1)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    async fn send(
+        tx: &mpsc::Sender<Command>,
+        command: impl FnOnce(oneshot::Sender<String>) -> Command,
+    ) -> String {
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(command(response_tx)).await.unwrap();
+        response_rx.await.unwrap()
+    }
+
+    fn apply(id: u64, op: Operation) -> impl FnOnce(oneshot::Sender<String>) -> Command {
+        move |respond| Command::Apply {
+            id: MachineId(id),
+            op,
+            respond,
+        }
+    }
+
+    fn status(id: u64) -> impl FnOnce(oneshot::Sender<String>) -> Command {
+        move |respond| Command::Status {
+            id: MachineId(id),
+            respond,
+        }
+    }
+
+    fn history(id: u64) -> impl FnOnce(oneshot::Sender<String>) -> Command {
+        move |respond| Command::History {
+            id: MachineId(id),
+            respond,
+        }
+    }
+
+    /// Moves tokio's paused clock forward and gives `run` the chance to react to it.
+    #[cfg(feature = "timing")]
+    async fn advance(duration: Duration) {
+        tokio::time::advance(duration).await;
+        tokio::task::yield_now().await;
+    }
+
+    #[tokio::test]
+    async fn run_applies_commands() {
+        let (tx, rx) = mpsc::channel(1);
+        let handle = tokio::spawn(run(Config::default(), rx, oneshot::channel().1));
+
+        assert_eq!(
+            "Transitioned to ready!",
+            send(&tx, apply(0, Operation::Ready)).await
+        );
+        assert_eq!(
+            "Transitioned to stored!",
+            send(&tx, apply(0, Operation::Store)).await
+        );
+        assert_eq!(
+            "Transition failed! can not store from the stored state, valid operations are: ready.",
+            send(&tx, apply(0, Operation::Store)).await
+        );
+
+        drop(tx);
+        let machines = handle.await.unwrap();
+        let state = machines[&MachineId(0)].current();
+        assert_eq!(StateKind::Stored, state.kind());
+        assert_eq!(1, state.ready_count());
+    }
+
+    #[tokio::test]
+    async fn machines_advance_independently() {
+        let (tx, rx) = mpsc::channel(1);
+        let handle = tokio::spawn(run(Config::default(), rx, oneshot::channel().1));
+
+        for op in [Operation::Ready, Operation::Store, Operation::Ready] {
+            send(&tx, apply(1, op)).await;
+        }
+        assert_eq!(
+            "Transitioned to ready!",
+            send(&tx, apply(2, Operation::Ready)).await
+        );
+        send(&tx, apply(2, Operation::Pause)).await;
+
+        drop(tx);
+        let machines = handle.await.unwrap();
+        assert_eq!(2, machines.len());
+        assert_eq!(StateKind::Ready, machines[&MachineId(1)].current().kind());
+        assert_eq!(2, machines[&MachineId(1)].current().ready_count());
+        assert_eq!(StateKind::Paused, machines[&MachineId(2)].current().kind());
+        assert_eq!(1, machines[&MachineId(2)].current().ready_count());
+    }
+
+    #[test]
+    fn process_batch_collects_responses() {
+        let (state, responses) = process_batch(
+            State::stored_with(0),
+            vec![Operation::Ready, Operation::Store],
+        );
+        assert_eq!(
+            vec!["Transitioned to ready!", "Transitioned to stored!"],
+            responses
+        );
+        assert_eq!(StateKind::Stored, state.kind());
+        assert_eq!(1, state.ready_count());
+
+        let (state, responses) = process_batch(state, Vec::new());
+        assert!(responses.is_empty());
+        assert_eq!(StateKind::Stored, state.kind());
+    }
+
+    #[cfg(feature = "timing")]
+    #[tokio::test]
+    async fn auto_stores_idle_ready_state() {
+        let config = Config {
+            auto_store_after: Some(Duration::from_millis(10)),
+            ..Config::default()
+        };
+        let (tx, rx) = mpsc::channel(1);
+        let handle = tokio::spawn(run(config, rx, oneshot::channel().1));
+
+        assert_eq!(
+            "Transitioned to ready!",
+            send(&tx, apply(0, Operation::Ready)).await
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        drop(tx);
+        let machines = handle.await.unwrap();
+        let state = machines[&MachineId(0)].current();
+        assert_eq!(StateKind::Stored, state.kind());
+        assert_eq!(1, state.ready_count());
+    }
+
+    #[tokio::test]
+    async fn status_does_not_transition() {
+        let (tx, rx) = mpsc::channel(1);
+        let handle = tokio::spawn(run(Config::default(), rx, oneshot::channel().1));
+
+        send(&tx, apply(0, Operation::Ready)).await;
+        let response = send(&tx, status(0)).await;
+        assert!(
+            response.starts_with("Current state is ready (count=1, age="),
+            "{response}"
+        );
+        send(&tx, status(0)).await;
+
+        drop(tx);
+        let machines = handle.await.unwrap();
+        let state = machines[&MachineId(0)].current();
+        assert_eq!(StateKind::Ready, state.kind());
+        assert_eq!(1, state.ready_count());
+    }
+
+    #[tokio::test]
+    async fn history_lists_entered_states() {
+        let (tx, rx) = mpsc::channel(1);
+        let handle = tokio::spawn(run(Config::default(), rx, oneshot::channel().1));
+
+        send(&tx, apply(0, Operation::Ready)).await;
+        send(&tx, apply(0, Operation::Store)).await;
+        send(&tx, apply(0, Operation::Store)).await;
+        let response = send(&tx, history(0)).await;
+        let lines: Vec<_> = response.lines().collect();
+        assert_eq!(3, lines.len(), "{response}");
+        assert!(lines[0].starts_with("1. stored at +"), "{response}");
+        assert!(lines[1].starts_with("2. ready at +"), "{response}");
+        assert!(lines[2].starts_with("3. stored at +"), "{response}");
+
+        drop(tx);
+        handle.await.unwrap();
+    }
+
+    #[test]
+    fn shutdown_exits_current_states() {
+        use crate::capture::capture;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let ((machines, (response, _tx)), records) = capture(|| {
+            runtime.block_on(async {
+                let (tx, rx) = mpsc::channel(1);
+                let (shutdown_tx, shutdown_rx) = oneshot::channel();
+                tokio::join!(run(Config::default(), rx, shutdown_rx), async {
+                    let response = send(&tx, apply(0, Operation::Ready)).await;
+                    shutdown_tx.send(()).unwrap();
+                    // The channel is still open, so only the shutdown can stop the loop.
+                    (response, tx)
+                })
+            })
+        });
+        assert_eq!("Transitioned to ready!", response);
+        assert!(machines.is_empty());
+        assert!(
+            records
+                .iter()
+                .any(|record| record.field("state") == Some("\"ready\"")),
+            "exiting the ready state should be logged"
+        );
+    }
+
+    #[test]
+    fn dropped_receiver_warns() {
+        use crate::capture::capture;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (machines, records) = capture(|| {
+            runtime.block_on(async {
+                let (tx, rx) = mpsc::channel(1);
+                let (response_tx, response_rx) = oneshot::channel();
+                drop(response_rx);
+                tx.send(apply(0, Operation::Ready)(response_tx))
+                    .await
+                    .unwrap();
+                drop(tx);
+                run(Config::default(), rx, oneshot::channel().1).await
+            })
+        });
+        assert!(
+            records.iter().any(|record| {
+                record.level() == Some(tracing::Level::WARN)
+                    && record.field("message") == Some("response receiver dropped")
+            }),
+            "the dropped receiver should be logged"
+        );
+        assert_eq!("ready", machines[&MachineId(0)].current().name());
+    }
+
+    #[tokio::test]
+    async fn machines_start_at_ready_count() {
+        let (tx, rx) = mpsc::channel(1);
+        let handle = tokio::spawn(run_with_ready_count(
+            Config::default(),
+            5,
+            rx,
+            oneshot::channel().1,
+        ));
+        send(&tx, apply(0, Operation::Ready)).await;
+        drop(tx);
+        let machines = handle.await.unwrap();
+        assert_eq!(6, machines[&MachineId(0)].current().ready_count());
+    }
+
+    #[tokio::test]
+    async fn notifies_async_observers_in_order() {
+        struct Recorder(Arc<Mutex<Vec<(&'static str, &'static str)>>>);
+
+        impl AsyncTransitionObserver for Recorder {
+            async fn on_transition(&self, report: TransitionReport) {
+                // Gives the run loop the chance to move on if it does not await the observer.
+                tokio::task::yield_now().await;
+                self.0.lock().unwrap().push((report.from, report.to));
+            }
+        }
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let mut observers = AsyncObservers::default();
+        observers.add(Recorder(Arc::clone(&reports)));
+        let (tx, rx) = mpsc::channel(1);
+        let handle = tokio::spawn(run_observed(
+            Config::default(),
+            0,
+            observers,
+            rx,
+            oneshot::channel().1,
+        ));
+        for op in [
+            Operation::Ready,
+            Operation::Pause,
+            Operation::Ready,
+            Operation::Store,
+        ] {
+            send(&tx, apply(0, op)).await;
+        }
+        drop(tx);
+        handle.await.unwrap();
+
+        assert_eq!(
+            vec![
+                ("stored", "ready"),
+                ("ready", "paused"),
+                ("paused", "stored")
+            ],
+            *reports.lock().unwrap(),
+            "rejected operations should not be reported"
+        );
+    }
+
+    #[tokio::test]
+    async fn help_describes_operations() {
+        let (tx, rx) = mpsc::channel(1);
+        let handle = tokio::spawn(run(Config::default(), rx, oneshot::channel().1));
+        let help = send(&tx, |respond| Command::Help { respond }).await;
+        assert!(help.contains("ready    Enters the ready state"), "{help}");
+        assert!(help.contains("store    Stores"), "{help}");
+        assert!(help.contains("rearm    Re-enters"), "{help}");
+        assert!(help.contains("help "), "{help}");
+        drop(tx);
+        assert!(
+            handle.await.unwrap().is_empty(),
+            "help should not create a machine"
+        );
+    }
+
+    #[test]
+    fn prompt_lists_legal_operations() {
+        let prompt_for = |state: State| prompt(&state.legal_operations());
+        let ask = "Please enter an operation, optionally prefixed by a machine id:";
+        assert_eq!(
+            format!("{ask} reset, status, history, help\n> "),
+            prompt_for(State::default())
+        );
+        assert_eq!(
+            format!("{ask} ready, reset, status, history, help\n> "),
+            prompt_for(State::stored_with(0))
+        );
+        assert_eq!(
+            format!("{ask} store, pause, rearm, reset, status, history, help\n> "),
+            prompt_for(State::ready_with(1))
+        );
+        assert_eq!(
+            format!("{ask} resume, store, reset, status, history, help\n> "),
+            prompt_for(State::ready_with(1).pause().unwrap())
+        );
+        assert_eq!(
+            format!("{ask} recover, reset, status, history, help\n> "),
+            prompt_for(State::stored_with(0).error("test".to_string()))
+        );
+    }
+
+    #[cfg(feature = "timing")]
+    #[tokio::test(start_paused = true)]
+    async fn auto_store_follows_virtual_time() {
+        let started = std::time::Instant::now();
+        let config = Config {
+            auto_store_after: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        let (tx, rx) = mpsc::channel(1);
+        let handle = tokio::spawn(run(config, rx, oneshot::channel().1));
+
+        send(&tx, apply(0, Operation::Ready)).await;
+        advance(Duration::from_secs(59)).await;
+        let response = send(&tx, status(0)).await;
+        assert!(response.starts_with("Current state is ready"), "{response}");
+        advance(Duration::from_secs(1)).await;
+        let response = send(&tx, status(0)).await;
+        assert!(
+            response.starts_with("Current state is stored"),
+            "{response}"
+        );
+
+        drop(tx);
+        handle.await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,35 @@
+use crate::*;
+
+impl State {
+    /// The JSON schema of a `State` as written by its `serde::Serialize` implementation, for
+    /// consumers that are not written in Rust. Fields that are skipped during serialization, like
+    /// the timers, are not part of it.
+    pub fn json_schema() -> String {
+        schemars::schema_for!(State).as_value().to_string()
+    }
+}
+
+impl StateSnapshot {
+    /// The JSON schema of a serialized `StateSnapshot`.
+    pub fn json_schema() -> String {
+        schemars::schema_for!(StateSnapshot).as_value().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schemas_describe_serialized_fields() {
+        let schema: serde_json::Value =
+            serde_json::from_str(&State::json_schema()).expect("the schema should be valid JSON");
+        let stored = &schema["$defs"]["StoredState"]["properties"];
+        assert!(stored.get("ready_count").is_some(), "{schema}");
+        assert!(stored.get("clock").is_none(), "{schema}");
+
+        let schema = StateSnapshot::json_schema();
+        assert!(schema.contains("\"name\""), "{schema}");
+        assert!(schema.contains("\"ready_count\""), "{schema}");
+    }
+}
@@ -0,0 +1,108 @@
+use crate::*;
+use std::time::Duration;
+
+/// A read-only picture of the observable parts of a `State`. Unlike `State`, it is cheap to clone
+/// and compare.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct StateSnapshot {
+    pub name: &'static str,
+    pub ready_count: u64,
+    pub elapsed: Duration,
+}
+
+impl StateSnapshot {
+    /// The change in the ready count since `earlier`. This is negative when the count went down,
+    /// like after a reset, and saturates at the bounds of `i64`.
+    pub fn delta(&self, earlier: &StateSnapshot) -> i64 {
+        let delta = i128::from(self.ready_count) - i128::from(earlier.ready_count);
+        delta.clamp(i128::from(i64::MIN), i128::from(i64::MAX)) as i64
+    }
+}
+
+impl State {
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            name: self.name(),
+            ready_count: self.ready_count(),
+            elapsed: self.elapsed(),
+        }
+    }
+
+    /// Reconstructs the state that `snapshot` was taken of, with exactly its ready count and fresh
+    /// timers. Like `load_from_path`, an error state is restored as a recoverable error. Snapshots
+    /// always name a known state, but should one not, the result is a stored state with its ready
+    /// count.
+    pub fn from_snapshot(snapshot: &StateSnapshot) -> State {
+        crate::persist::restore(snapshot.name, snapshot.ready_count).unwrap_or_else(|| {
+            tracing::warn!(
+                name = snapshot.name,
+                "Restoring an unknown state as stored."
+            );
+            State::stored_with(snapshot.ready_count)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_does_not_consume_state() {
+        let state = State::stored_with(0)
+            .ready()
+            .expect("should be able to transition from stored to ready");
+        let snapshot = state.snapshot();
+        assert_eq!("ready", snapshot.name);
+        assert_eq!(1, snapshot.ready_count);
+        assert!(snapshot.elapsed <= state.elapsed());
+        assert_eq!(snapshot, snapshot.clone());
+
+        let state = state
+            .store()
+            .expect("should be able to transition from ready to stored");
+        assert_eq!("stored", state.snapshot().name);
+    }
+
+    #[test]
+    fn delta_between_snapshots() {
+        let earlier = State::ready_with(1).snapshot();
+        let later = State::ready_with(4).snapshot();
+        assert_eq!(3, later.delta(&earlier));
+        assert_eq!(-3, earlier.delta(&later));
+        assert_eq!(0, later.delta(&later));
+
+        let max = State::stored_with(u64::MAX).snapshot();
+        let min = State::stored_with(0).snapshot();
+        assert_eq!(i64::MAX, max.delta(&min));
+        assert_eq!(i64::MIN, min.delta(&max));
+    }
+
+    #[test]
+    fn from_snapshot_round_trip() {
+        let states = [
+            State::default(),
+            State::stored_with(3),
+            State::ready_with(4),
+            State::ready_with(5).pause().unwrap(),
+            State::stored_with(6).error_with("test".to_string(), false),
+        ];
+        for state in states {
+            let snapshot = state.snapshot();
+            let restored = State::from_snapshot(&snapshot);
+            assert_eq!(state.kind(), restored.kind(), "{snapshot:?}");
+            assert_eq!(state.ready_count(), restored.ready_count(), "{snapshot:?}");
+        }
+
+        let unknown = StateSnapshot {
+            name: "unknown",
+            ready_count: 7,
+            elapsed: Duration::ZERO,
+        };
+        let restored = State::from_snapshot(&unknown);
+        assert_eq!(StateKind::Stored, restored.kind());
+        assert_eq!(7, restored.ready_count());
+    }
+}
@@ -1,28 +1,112 @@
+use crate::exit_guard::ExitGuard;
 use crate::*;
 pub use internal::*;
-use std::time::Instant;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::Span;
 
 mod internal {
     use super::*;
 
-    #[derive(Debug)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
     pub struct StoredState {
         ready_count: u64,
-        stored_start: Instant,
+        transition_count: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        overflowed: bool,
+        #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+        clock: Arc<dyn Clock>,
+        #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+        logger: Arc<dyn StateLogger>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        transform: Option<Arc<dyn TransitionTransform>>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        config: Config,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        last_request_id: Option<u64>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        previous: Option<&'static str>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        time_in_previous: Duration,
+        #[cfg_attr(feature = "serde", serde(skip, default = "Timestamp::now"))]
+        stored_start: Timestamp,
+        #[cfg_attr(feature = "serde", serde(skip, default = "stored_exit_guard"))]
+        exit_guard: ExitGuard,
+        // The span is opened in `enter` and closed when it is dropped in `exit`. We store the span
+        // itself rather than an entered span guard because the guard can not be sent to other
+        // threads, which would make the state unusable in most async code.
+        #[cfg_attr(feature = "serde", serde(skip, default = "Span::none"))]
+        span: Span,
     }
 
     // This implementation block can access private fields in StoredState. Only add methods here
     // that need this level of access.
     impl StoredState {
-        /// This method needs to be called to construct an instance of the state. This means it will
-        /// always be called when entering this state.
+        /// This method (or `try_enter`) needs to be called to construct an instance of the state.
+        /// This means it will always be called when entering this state. Panics if the inputs are
+        /// invalid.
         pub fn enter(inputs: StoredStateInputs) -> Self {
-            let StoredStateInputs { ready_count } = inputs;
+            Self::try_enter(inputs).expect("the inputs should be valid")
+        }
+
+        /// Like `enter`, but rejects invalid inputs instead of panicking.
+        pub fn try_enter(inputs: StoredStateInputs) -> Result<Self, EnterError> {
+            inputs.config.validate()?;
+
+            let StoredStateInputs {
+                ready_count,
+                transition_count,
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                from,
+                time_in_previous,
+                now,
+            } = inputs;
 
-            Self {
+            let CarriedData {
                 ready_count,
-                stored_start: Instant::now(),
+                last_request_id,
+            } = CarriedData {
+                ready_count,
+                last_request_id,
             }
+            .transformed(transform.as_deref(), from, StateKind::Stored, &config);
+
+            let stored_start = now.unwrap_or_else(|| clock.timestamp());
+            let state = Self {
+                ready_count,
+                transition_count,
+                overflowed,
+                stored_start,
+                exit_guard: ExitGuard::new("stored", Arc::clone(&clock), stored_start),
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                previous: from,
+                time_in_previous,
+                span: tracing::info_span!(
+                    "stored",
+                    ready_count,
+                    last_request_id = tracing::field::Empty
+                ),
+            };
+            state.log_entered();
+            Ok(state)
+        }
+
+        fn log_entered(&self) {
+            self.span
+                .in_scope(|| self.logger.state_entered("stored", self.ready_count));
         }
 
         /// This method needs to be called when transitioning away from the stored state because it
@@ -31,30 +115,197 @@ mod internal {
         pub fn exit(self) -> StoredStateOutputs {
             let Self {
                 ready_count,
+                transition_count,
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                previous: _,
+                time_in_previous: _,
                 stored_start,
+                span,
+                exit_guard,
             } = self;
+            exit_guard.disarm();
 
-            tracing::info!("Spent {:?} in stored state.", stored_start.elapsed());
+            let time_in_state = clock.timestamp().saturating_duration_since(stored_start);
+            // The id may have been set after entering, so it is only recorded on the span now.
+            span.record("last_request_id", last_request_id);
+            span.in_scope(|| logger.state_exited("stored", time_in_state, ready_count));
 
-            StoredStateOutputs { ready_count }
+            // Leaving a state always means a transition is taking place.
+            StoredStateOutputs {
+                ready_count,
+                transition_count: transition_count.saturating_add(1),
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                time_in_state,
+            }
         }
 
         pub fn ready_count(&self) -> u64 {
             self.ready_count
         }
+
+        /// The number of transitions that have been made before entering this state.
+        pub fn transition_count(&self) -> u64 {
+            self.transition_count
+        }
+
+        pub fn overflowed(&self) -> bool {
+            self.overflowed
+        }
+
+        pub fn clock(&self) -> &Arc<dyn Clock> {
+            &self.clock
+        }
+
+        /// The configuration, which is carried forward through all transitions.
+        pub fn config(&self) -> &Config {
+            &self.config
+        }
+
+        /// The id of the last request that transitioned the state machine, if any.
+        pub fn last_request_id(&self) -> Option<u64> {
+            self.last_request_id
+        }
+
+        /// Tags the state with the id of the request that is about to transition it, so the id
+        /// shows up in the logs of `exit`.
+        pub(crate) fn set_last_request_id(&mut self, last_request_id: Option<u64>) {
+            self.last_request_id = last_request_id;
+        }
+
+        /// The name of the state we transitioned from to get here, or `None` if the state machine
+        /// started in this state.
+        pub fn previous(&self) -> Option<&'static str> {
+            self.previous
+        }
+
+        /// How long we spent in the previous state, or zero if there is none.
+        pub fn time_in_previous(&self) -> Duration {
+            self.time_in_previous
+        }
+
+        /// The span that is open while we are in the stored state. Enter it to associate logs
+        /// with this state.
+        pub fn span(&self) -> &Span {
+            &self.span
+        }
+
+        /// When we entered the stored state.
+        pub fn entered_at(&self) -> Timestamp {
+            self.stored_start
+        }
+
+        /// How long we have been in the stored state so far.
+        pub fn elapsed(&self) -> Duration {
+            self.clock
+                .timestamp()
+                .saturating_duration_since(self.stored_start)
+        }
+    }
+
+    // The clock, logger, span and start time are left out so that the output does not depend on
+    // when it is printed.
+    impl fmt::Debug for StoredState {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("StoredState")
+                .field("ready_count", &self.ready_count)
+                .field("transition_count", &self.transition_count)
+                .field("overflowed", &self.overflowed)
+                .field("config", &self.config)
+                .field("last_request_id", &self.last_request_id)
+                .field("previous", &self.previous)
+                .field("time_in_previous", &self.time_in_previous)
+                .finish_non_exhaustive()
+        }
     }
 }
 
+#[cfg(feature = "serde")]
+fn stored_exit_guard() -> ExitGuard {
+    ExitGuard::new("stored", system_clock(), Timestamp::now())
+}
+
 /// This defines the inputs required to enter the StoredState.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StoredStateInputs {
     pub ready_count: u64,
+    pub transition_count: u64,
+    /// Whether the ready count has ever overflowed, see `State::has_overflowed`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overflowed: bool,
+    /// The clock used to measure time, which is carried forward through all transitions.
+    #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+    pub clock: Arc<dyn Clock>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+    pub logger: Arc<dyn StateLogger>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub transform: Option<Arc<dyn TransitionTransform>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub config: Config,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_request_id: Option<u64>,
+    /// The name of the state we are transitioning from, if any.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub from: Option<&'static str>,
+    /// How long we spent in the state we are transitioning from.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub time_in_previous: Duration,
+    /// When the state is entered, or `None` to ask the clock. Tests can pass a fixed instant to
+    /// get deterministic timestamps.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub now: Option<Timestamp>,
+}
+
+impl StoredStateInputs {
+    /// Creates inputs for a state machine that has not made any transitions yet.
+    pub fn new(ready_count: u64) -> Self {
+        Self {
+            ready_count,
+            transition_count: 0,
+            overflowed: false,
+            clock: system_clock(),
+            logger: tracing_logger(),
+            transform: None,
+            config: Config::default(),
+            from: None,
+            last_request_id: None,
+            time_in_previous: Duration::ZERO,
+            now: None,
+        }
+    }
 }
 
 /// This defines the outputs provided when leaving the StoredState.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StoredStateOutputs {
     pub ready_count: u64,
+    pub transition_count: u64,
+    /// Whether the ready count has ever overflowed, see `State::has_overflowed`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overflowed: bool,
+    #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+    pub clock: Arc<dyn Clock>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+    pub logger: Arc<dyn StateLogger>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub transform: Option<Arc<dyn TransitionTransform>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub config: Config,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_request_id: Option<u64>,
+    /// How long we spent in the state that was exited.
+    pub time_in_state: Duration,
 }
 
 // Instead of returning any `State`, we define this type which contains only a subset of `State`
@@ -75,18 +326,170 @@ impl_state_transition_result! {
 // guarantee that we can not access the private fields. This is necessary to enforce calling the
 // `exit` method.
 impl StoredState {
+    /// Transitions to the ready state without checking the configured `max_ready_count`. When the
+    /// ready count would overflow in `OverflowMode::Error`, it is left as is. Use `ready_checked`
+    /// to reject the transition in these cases instead.
     pub fn ready(self) -> StoredStateTransitionResult {
         // This will not compile, which is the intention, because the fields are inaccessible here.
         // let Self {
         //     ready_count,
+        //     transition_count,
+        //     clock,
         //     stored_start,
         // } = self;
 
-        let StoredStateOutputs { ready_count } = self.exit();
+        let StoredStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
 
         // The associated function ReadyState::enter takes care of incrementing the ready count so
         // that it always happens, regardless of which state we are coming from.
-        StoredStateTransitionResult::Ready(ReadyState::enter(ReadyStateInputs { ready_count }))
+        StoredStateTransitionResult::Ready(ReadyState::enter(ReadyStateInputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            from: Some("stored"),
+            time_in_previous: time_in_state,
+            now: None,
+        }))
+    }
+
+    /// Like `ready`, but passes `payload` into the ready state where it can be read with
+    /// `ReadyState::payload`.
+    pub fn ready_with_payload<T>(self, payload: T) -> ReadyState<T> {
+        let StoredStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
+
+        ReadyState::enter_with_payload(
+            ReadyStateInputs {
+                ready_count,
+                transition_count,
+                overflowed,
+                clock,
+                logger,
+                transform,
+                config,
+                last_request_id,
+                from: Some("stored"),
+                time_in_previous: time_in_state,
+                now: None,
+            },
+            payload,
+        )
+    }
+
+    /// Like `ready`, but hands back the state unchanged when entering the ready state would exceed
+    /// the configured `max_ready_count` or overflow in `OverflowMode::Error`.
+    pub fn ready_checked(self) -> Result<StoredStateTransitionResult, StoredState> {
+        if self.config().next_ready_count(self.ready_count()).is_none() {
+            return Err(self);
+        }
+
+        Ok(self.ready())
+    }
+
+    /// Like `ready_checked`, but also refuses to transition when entering the ready state would
+    /// increase the ready count beyond `max_ready_count`, taking the configured `step` and
+    /// `overflow_mode` into account. The state is handed back unchanged in that case.
+    pub fn try_ready(
+        self,
+        max_ready_count: u64,
+    ) -> Result<StoredStateTransitionResult, StoredState> {
+        let next = self.config().next_ready_count(self.ready_count());
+        if next.is_none_or(|next| next > max_ready_count) {
+            return Err(self);
+        }
+
+        Ok(self.ready())
+    }
+
+    /// Like `ready`, but only transitions when the asynchronous `guard` resolves to `true`. The
+    /// guard is awaited before `exit` is called, so a rejected transition hands back the state
+    /// untouched. The same goes for cancelling `cancel` before the guard resolves, in which case
+    /// the guard is dropped.
+    pub async fn ready_async<F, Fut>(
+        self,
+        guard: F,
+        cancel: &CancellationToken,
+    ) -> Result<StoredStateTransitionResult, (Self, TransitionError)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let allowed = tokio::select! {
+            // Cancellation wins if the guard happens to resolve at the same time.
+            biased;
+            _ = cancel.cancelled() => {
+                let error = TransitionError::Cancelled {
+                    operation: "ready",
+                    state: "stored",
+                };
+                return Err((self, error));
+            }
+            allowed = guard() => allowed,
+        };
+        if !allowed {
+            let error = TransitionError::Denied {
+                operation: "ready",
+                state: "stored",
+            };
+            return Err((self, error));
+        }
+
+        Ok(self.ready())
+    }
+
+    // Every state can transition to the error state, so there is no point in declaring a
+    // transition result type. We return the `ErrorState` directly instead.
+    pub fn error(self, reason: String, recoverable: bool) -> ErrorState {
+        let StoredStateOutputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            time_in_state,
+        } = self.exit();
+
+        ErrorState::enter(ErrorStateInputs {
+            ready_count,
+            transition_count,
+            overflowed,
+            clock,
+            logger,
+            transform,
+            config,
+            last_request_id,
+            from: Some("stored"),
+            time_in_previous: time_in_state,
+            reason,
+            recoverable,
+        })
     }
 }
 
@@ -98,7 +501,7 @@ mod tests {
 
     #[test]
     fn test() {
-        let state = StoredState::enter(StoredStateInputs { ready_count: 0 });
+        let state = StoredState::enter(StoredStateInputs::new(0));
         assert_eq!(
             0,
             state.ready_count(),
@@ -110,4 +513,239 @@ mod tests {
             "should be able to transition to the ready state"
         );
     }
+
+    #[test]
+    fn ready_remembers_previous_state() {
+        let state = StoredState::enter(StoredStateInputs::new(0));
+        assert_eq!(None, state.previous());
+        let StoredStateTransitionResult::Ready(state) = state.ready();
+        assert_eq!(Some("stored"), state.previous());
+    }
+
+    #[test]
+    fn span_is_open_while_in_state() {
+        use crate::capture::{capture, Record};
+
+        let (_, records) = capture(|| {
+            let state = StoredState::enter(StoredStateInputs::new(0));
+            let StoredStateTransitionResult::Ready(state) = state.ready();
+            state
+        });
+        let records: Vec<_> = records.into_iter().filter(Record::is_span).collect();
+        assert_eq!(
+            vec![
+                Record::SpanOpened("stored"),
+                Record::SpanClosed("stored"),
+                Record::SpanOpened("ready"),
+            ],
+            records
+        );
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn exit_records_structured_event() {
+        use crate::capture::capture;
+
+        let (_, records) = capture(|| {
+            let state = StoredState::enter(StoredStateInputs::new(4));
+            state.exit()
+        });
+        // Entering records an event for the same state, which comes first.
+        let event = records
+            .iter()
+            .rfind(|record| record.field("state") == Some("\"stored\""))
+            .expect("exiting should record an event");
+        assert!(event.field("duration_ms").is_some());
+        assert_eq!(Some("4"), event.field("ready_count"));
+        assert!(event.field("message").is_some());
+    }
+
+    #[test]
+    fn try_ready_below_limit() {
+        let state = StoredState::enter(StoredStateInputs::new(2));
+        let state = state
+            .try_ready(3)
+            .expect("should be able to reach the limit");
+        let StoredStateTransitionResult::Ready(state) = state;
+        assert_eq!(3, state.ready_count());
+    }
+
+    #[test]
+    fn try_ready_at_limit() {
+        let state = StoredState::enter(StoredStateInputs::new(3));
+        let state = state
+            .try_ready(3)
+            .expect_err("should not be able to exceed the limit");
+        assert_eq!(
+            3,
+            state.ready_count(),
+            "the rejected state should be preserved"
+        );
+    }
+
+    #[test]
+    fn try_ready_respects_step() {
+        let config = Config {
+            step: 5,
+            ..Config::default()
+        };
+        let state = stored_with(2, config.clone())
+            .try_ready(3)
+            .expect_err("a step of 5 should exceed the limit");
+        assert_eq!(2, state.ready_count());
+        let StoredStateTransitionResult::Ready(state) = stored_with(2, config)
+            .try_ready(7)
+            .expect("a step of 5 should reach the limit");
+        assert_eq!(7, state.ready_count());
+
+        stored_with(u64::MAX, overflow_config(OverflowMode::Wrap))
+            .try_ready(u64::MAX)
+            .expect("wrapping around should stay below the limit");
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn enter_at_given_instant() {
+        let clock = MockClock::new();
+        let now = clock.now();
+        clock.advance(Duration::from_secs(2));
+        let state = StoredState::enter(StoredStateInputs {
+            clock: Arc::new(clock.clone()),
+            logger: tracing_logger(),
+            transform: None,
+            now: Some(now),
+            ..StoredStateInputs::new(0)
+        });
+        assert_eq!(now, state.entered_at());
+        assert_eq!(Duration::from_secs(2), state.elapsed());
+
+        let state = StoredState::enter(StoredStateInputs {
+            clock: Arc::new(clock.clone()),
+            ..StoredStateInputs::new(0)
+        });
+        assert_eq!(clock.now(), state.entered_at(), "defaults to the clock");
+    }
+
+    fn stored_with(ready_count: u64, config: Config) -> StoredState {
+        StoredState::enter(StoredStateInputs {
+            config,
+            ..StoredStateInputs::new(ready_count)
+        })
+    }
+
+    fn overflow_config(overflow_mode: OverflowMode) -> Config {
+        Config {
+            overflow_mode,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn ready_checked_overflow_at_max() {
+        let state = stored_with(u64::MAX, overflow_config(OverflowMode::Saturate));
+        let StoredStateTransitionResult::Ready(state) =
+            state.ready_checked().expect("saturating can not fail");
+        assert_eq!(u64::MAX, state.ready_count());
+
+        let state = stored_with(u64::MAX, overflow_config(OverflowMode::Wrap));
+        let StoredStateTransitionResult::Ready(state) =
+            state.ready_checked().expect("wrapping can not fail");
+        assert_eq!(0, state.ready_count());
+
+        let state = stored_with(u64::MAX, overflow_config(OverflowMode::Error));
+        let state = state
+            .ready_checked()
+            .expect_err("the ready count should not overflow");
+        assert_eq!(
+            u64::MAX,
+            state.ready_count(),
+            "the rejected state should be preserved"
+        );
+        assert_eq!(0, state.transition_count());
+    }
+
+    #[test]
+    fn ready_checked_at_max_ready_count() {
+        let config = Config {
+            max_ready_count: Some(3),
+            ..Config::default()
+        };
+        let StoredStateTransitionResult::Ready(state) = stored_with(2, config.clone())
+            .ready_checked()
+            .expect("should be able to reach the limit");
+        assert_eq!(3, state.ready_count());
+        let state = stored_with(3, config)
+            .ready_checked()
+            .expect_err("should not be able to exceed the limit");
+        assert_eq!(3, state.ready_count());
+    }
+
+    #[tokio::test]
+    async fn ready_async_accepted() {
+        let state = StoredState::enter(StoredStateInputs::new(0));
+        let state = state
+            .ready_async(|| async { true }, &CancellationToken::new())
+            .await
+            .expect("the guard allows the transition");
+        let StoredStateTransitionResult::Ready(state) = state;
+        assert_eq!(1, state.ready_count());
+    }
+
+    #[tokio::test]
+    async fn ready_async_rejected() {
+        let state = StoredState::enter(StoredStateInputs::new(0));
+        let (state, error) = state
+            .ready_async(|| async { false }, &CancellationToken::new())
+            .await
+            .expect_err("the guard rejects the transition");
+        assert_eq!(0, state.ready_count());
+        assert!(matches!(error, TransitionError::Denied { .. }), "{error}");
+    }
+
+    #[tokio::test]
+    async fn ready_async_cancelled() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let state = StoredState::enter(StoredStateInputs::new(0));
+        let (state, error) = state
+            .ready_async(std::future::pending, &cancel)
+            .await
+            .expect_err("the transition was cancelled");
+        assert_eq!(
+            TransitionError::Cancelled {
+                operation: "ready",
+                state: "stored",
+            },
+            error
+        );
+        assert_eq!(0, state.ready_count());
+        // Handing back the state proves that `exit`, which consumes it, was never called.
+        assert_eq!(0, state.transition_count());
+    }
+
+    #[test]
+    fn debug_omits_timer() {
+        let debug = format!("{:?}", StoredState::enter(StoredStateInputs::new(1)));
+        assert!(debug.starts_with("StoredState {"), "{debug}");
+        assert!(debug.contains("ready_count: 1"), "{debug}");
+        assert!(!debug.contains("Instant"), "{debug}");
+    }
+
+    #[test]
+    fn drop_without_exit_warns() {
+        use crate::capture::capture;
+
+        let (_, records) = capture(|| drop(StoredState::enter(StoredStateInputs::new(0))));
+        let warning = records
+            .iter()
+            .find(|record| record.level() == Some(tracing::Level::WARN))
+            .expect("dropping the state should log a warning");
+        assert_eq!(Some("\"stored\""), warning.field("state"));
+
+        let (_, records) = capture(|| StoredState::enter(StoredStateInputs::new(0)).exit());
+        assert!(records
+            .iter()
+            .all(|record| record.level() != Some(tracing::Level::WARN)));
+    }
 }
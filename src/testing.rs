@@ -0,0 +1,43 @@
+//! Constructors that give exact control over the ready count, for testing integrations with this
+//! crate. They are only available with the `testing` feature to keep them out of the API that
+//! production code sees. They are shorthands for `StoredState::enter` and `ReadyState::restore`,
+//! which are public regardless of the feature, so they do not grant anything that production code
+//! could not already do.
+
+use crate::*;
+
+impl StoredState {
+    /// Enters the stored state with exactly the given ready count.
+    pub fn test_with(ready_count: u64) -> Self {
+        StoredState::enter(StoredStateInputs::new(ready_count))
+    }
+}
+
+impl ReadyState {
+    /// Creates a ready state with exactly the given ready count, without the increment that
+    /// entering the ready state normally performs.
+    pub fn test_with(ready_count: u64) -> Self {
+        ReadyState::restore(ready_count)
+    }
+}
+
+impl State {
+    /// A ready state with exactly the given ready count, see `ReadyState::test_with`.
+    pub fn test_ready(ready_count: u64) -> Self {
+        State::Ready(ReadyState::test_with(ready_count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constructors_set_exact_counts() {
+        assert_eq!(3, StoredState::test_with(3).ready_count());
+        assert_eq!(3, ReadyState::test_with(3).ready_count());
+        let state = State::test_ready(3);
+        assert_eq!(StateKind::Ready, state.kind());
+        assert_eq!(3, state.ready_count());
+    }
+}
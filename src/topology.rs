@@ -0,0 +1,134 @@
+//! The static structure of the state machine: which states exist and which operations lead from one
+//! state to another.
+
+/// The names of all states, as returned by `State::name`.
+pub const STATES: &[&str] = &["uninitialized", "stored", "ready", "paused", "error"];
+
+/// All transitions as `(from, operation, to)`. Entering the error state through `State::error` and
+/// starting over through `State::reset` are possible from every state and are therefore not listed
+/// here.
+///
+/// This table is the single source of truth for the topology of the state machine.
+/// `StateKind::TRANSITIONS` and `State::available_operations` are derived from it at compile time.
+/// It has to be kept in sync with the transition methods on `State`, which is verified by the
+/// tests.
+///
+/// The transitions out of a state are declared in order of priority, see
+/// `State::available_operations`.
+pub const TRANSITIONS: &[(&str, &str, &str)] = &[
+    ("uninitialized", "initialize", "stored"),
+    ("stored", "ready", "ready"),
+    ("ready", "store", "stored"),
+    ("ready", "pause", "paused"),
+    ("ready", "rearm", "ready"),
+    ("paused", "resume", "ready"),
+    ("paused", "store", "stored"),
+    ("error", "recover", "stored"),
+];
+
+/// Compares two names in a `const` context, where `==` on `str` is not available.
+pub(crate) const fn names_equal(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut index = 0;
+    while index < a.len() {
+        if a[index] != b[index] {
+            return false;
+        }
+        index += 1;
+    }
+    true
+}
+
+/// Iterates over every edge of the state machine as `(from, operation, to)`.
+pub fn debug_transitions() -> impl Iterator<Item = (&'static str, &'static str, &'static str)> {
+    TRANSITIONS.iter().copied()
+}
+
+/// Renders the states and transitions as a Graphviz DOT graph.
+pub fn graph_dot() -> String {
+    let mut dot = String::from("digraph state_machine {\n");
+    for state in STATES {
+        dot.push_str(&format!("    {state};\n"));
+    }
+    for (from, operation, to) in debug_transitions() {
+        dot.push_str(&format!("    {from} -> {to} [label=\"{operation}\"];\n"));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Renders the states and transitions as a Mermaid `stateDiagram-v2`, which GitHub renders in
+/// Markdown. State names are capitalized to follow the Mermaid convention and the initial state
+/// is marked with an arrow from `[*]`.
+pub fn mermaid() -> String {
+    let mut diagram = String::from("stateDiagram-v2\n");
+    diagram.push_str(&format!("    [*] --> {}\n", capitalize(STATES[0])));
+    for (from, operation, to) in debug_transitions() {
+        diagram.push_str(&format!(
+            "    {} --> {} : {operation}\n",
+            capitalize(from),
+            capitalize(to)
+        ));
+    }
+    diagram
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_transitions_lists_edges() {
+        let edges: Vec<_> = debug_transitions().collect();
+        for edge in [
+            ("stored", "ready", "ready"),
+            ("ready", "store", "stored"),
+            ("ready", "rearm", "ready"),
+        ] {
+            assert_eq!(
+                1,
+                edges.iter().filter(|&&e| e == edge).count(),
+                "{edge:?} should be listed exactly once"
+            );
+        }
+        assert_eq!(TRANSITIONS.len(), edges.len());
+    }
+
+    #[test]
+    fn graph_dot_contains_states_and_transitions() {
+        let dot = graph_dot();
+        assert!(dot.starts_with("digraph"));
+        assert!(dot.contains("    stored;\n"));
+        assert!(dot.contains("    ready;\n"));
+        assert!(dot.contains("stored -> ready [label=\"ready\"];"));
+        assert!(dot.contains("ready -> stored [label=\"store\"];"));
+        assert!(dot.contains("ready -> ready [label=\"rearm\"];"));
+        assert_eq!(
+            dot.matches('{').count(),
+            dot.matches('}').count(),
+            "braces should balance"
+        );
+    }
+
+    #[test]
+    fn mermaid_contains_transitions() {
+        let diagram = mermaid();
+        assert!(diagram.starts_with("stateDiagram-v2\n"));
+        assert!(diagram.contains("    [*] --> Uninitialized\n"));
+        assert!(diagram.contains("Stored --> Ready : ready\n"));
+        assert!(diagram.contains("Ready --> Stored : store\n"));
+        assert!(diagram.contains("Ready --> Ready : rearm\n"));
+        assert_eq!(TRANSITIONS.len() + 2, diagram.lines().count());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::*;
+use std::fmt;
+
+/// The data that a transition carries from the state that is exited into the state that is
+/// entered, as far as a `TransitionTransform` may change it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CarriedData {
+    pub ready_count: u64,
+    pub last_request_id: Option<u64>,
+}
+
+/// Changes the data that is carried into the next state, for example to start the ready count over
+/// on certain transitions. Unlike a `TransitionObserver`, which only watches, the transform runs
+/// in `enter` before the data is stored in the new state. Like the clock and the logger, it is
+/// carried forward through all transitions.
+pub trait TransitionTransform: fmt::Debug + Send + Sync {
+    /// Called when entering `to` from `from`. For the ready state, the ready count has already
+    /// been incremented.
+    fn transform(&self, from: StateKind, to: StateKind, carried: &mut CarriedData);
+}
+
+impl CarriedData {
+    /// Applies `transform`, if any, when entering `to` from the state named `from`. Entering the
+    /// initial state is not a transition and is left alone. The transformed ready count is capped
+    /// at `max_ready_count` so that a transform can not push it beyond what `ready` allows.
+    pub(crate) fn transformed(
+        mut self,
+        transform: Option<&dyn TransitionTransform>,
+        from: Option<&'static str>,
+        to: StateKind,
+        config: &Config,
+    ) -> Self {
+        let (Some(transform), Some(from)) = (transform, from.and_then(StateKind::from_name)) else {
+            return self;
+        };
+        transform.transform(from, to, &mut self);
+        if let Some(max_ready_count) = config.max_ready_count {
+            self.ready_count = self.ready_count.min(max_ready_count);
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Starts the ready count over whenever the ready state is stored.
+    #[derive(Debug)]
+    struct ZeroOnStore;
+
+    impl TransitionTransform for ZeroOnStore {
+        fn transform(&self, from: StateKind, to: StateKind, carried: &mut CarriedData) {
+            if (from, to) == (StateKind::Ready, StateKind::Stored) {
+                carried.ready_count = 0;
+            }
+        }
+    }
+
+    /// Tries to raise the ready count on every transition.
+    #[derive(Debug)]
+    struct Raise;
+
+    impl TransitionTransform for Raise {
+        fn transform(&self, _from: StateKind, _to: StateKind, carried: &mut CarriedData) {
+            carried.ready_count = u64::MAX;
+        }
+    }
+
+    fn stored_with(transform: Arc<dyn TransitionTransform>, config: Config) -> State {
+        State::Stored(StoredState::enter(StoredStateInputs {
+            transform: Some(transform),
+            config,
+            ..StoredStateInputs::new(0)
+        }))
+    }
+
+    #[test]
+    fn transform_zeroes_count_on_store() {
+        let state = stored_with(Arc::new(ZeroOnStore), Config::default())
+            .ready()
+            .expect("should be able to transition from stored to ready");
+        assert_eq!(1, state.ready_count());
+        let state = state
+            .pause()
+            .expect("should be able to transition from ready to paused");
+        assert_eq!(1, state.ready_count(), "only storing from ready zeroes");
+        let state = state
+            .resume()
+            .and_then(State::store)
+            .expect("should be able to transition from paused to ready to stored");
+        assert_eq!("stored", state.name());
+        assert_eq!(0, state.ready_count());
+    }
+
+    #[test]
+    fn transform_is_capped_at_max_ready_count() {
+        let config = Config {
+            max_ready_count: Some(3),
+            ..Config::default()
+        };
+        let state = stored_with(Arc::new(Raise), config)
+            .ready()
+            .expect("should be able to transition from stored to ready");
+        assert_eq!(3, state.ready_count());
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{format_duration, EnterError};
+use std::fmt;
+use std::time::Duration;
+
+/// Describes why a transition was rejected. It is returned alongside the unchanged state so the
+/// caller can both recover the state and report what went wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransitionError {
+    /// The operation is not legal from the current state.
+    Illegal {
+        /// The operation that was attempted.
+        operation: &'static str,
+        /// The name of the state that rejected the operation.
+        state: &'static str,
+        /// The operations that are legal from the state that rejected the operation.
+        valid_operations: &'static [&'static str],
+    },
+    /// The operation is legal, but was vetoed by a guard like the `TransitionGuard` of the
+    /// `StateMachine`.
+    Denied {
+        /// The operation that was attempted.
+        operation: &'static str,
+        /// The name of the state in which the operation was attempted.
+        state: &'static str,
+    },
+    /// An asynchronous transition was cancelled before it completed.
+    Cancelled {
+        /// The operation that was attempted.
+        operation: &'static str,
+        /// The name of the state in which the operation was attempted.
+        state: &'static str,
+    },
+    /// A transition was attempted while another one was in progress on the same `StateMachine`,
+    /// for example from an observer.
+    Reentrant {
+        /// The operation that was attempted.
+        operation: &'static str,
+        /// The name of the state that the transition in progress is leaving.
+        state: &'static str,
+    },
+    /// The operation is legal, but its configuration is invalid, see `Config::validate`.
+    InvalidConfig {
+        /// The operation that was attempted.
+        operation: &'static str,
+        /// The name of the state in which the operation was attempted.
+        state: &'static str,
+        /// What is wrong with the configuration.
+        error: EnterError,
+    },
+    /// The operation is legal, but too many transitions happened recently, see
+    /// `StateMachine::set_rate_limit`.
+    RateLimited {
+        /// The operation that was attempted.
+        operation: &'static str,
+        /// The name of the state in which the operation was attempted.
+        state: &'static str,
+        /// How long to wait before the next transition is allowed.
+        retry_after: Duration,
+    },
+}
+
+impl TransitionError {
+    /// The operation that was attempted.
+    pub fn operation(&self) -> &'static str {
+        match self {
+            TransitionError::Illegal { operation, .. }
+            | TransitionError::Denied { operation, .. }
+            | TransitionError::Cancelled { operation, .. }
+            | TransitionError::Reentrant { operation, .. }
+            | TransitionError::InvalidConfig { operation, .. }
+            | TransitionError::RateLimited { operation, .. } => operation,
+        }
+    }
+
+    /// The name of the state in which the operation was attempted.
+    pub fn state(&self) -> &'static str {
+        match self {
+            TransitionError::Illegal { state, .. }
+            | TransitionError::Denied { state, .. }
+            | TransitionError::Cancelled { state, .. }
+            | TransitionError::Reentrant { state, .. }
+            | TransitionError::InvalidConfig { state, .. }
+            | TransitionError::RateLimited { state, .. } => state,
+        }
+    }
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransitionError::Illegal {
+                operation,
+                state,
+                valid_operations,
+            } => {
+                write!(f, "can not {operation} from the {state} state, ")?;
+                if valid_operations.is_empty() {
+                    write!(f, "there are no valid operations")
+                } else {
+                    write!(f, "valid operations are: {}", valid_operations.join(", "))
+                }
+            }
+            TransitionError::Denied { operation, state } => {
+                write!(f, "{operation} from the {state} state was denied")
+            }
+            TransitionError::Cancelled { operation, state } => {
+                write!(f, "{operation} from the {state} state was cancelled")
+            }
+            TransitionError::Reentrant { operation, state } => write!(
+                f,
+                "{operation} was attempted while leaving the {state} state"
+            ),
+            TransitionError::InvalidConfig {
+                operation,
+                state,
+                error,
+            } => write!(
+                f,
+                "{operation} from the {state} state was rejected: {error}"
+            ),
+            TransitionError::RateLimited {
+                operation,
+                state,
+                retry_after,
+            } => write!(
+                f,
+                "{operation} from the {state} state was rate limited, retry after {}",
+                format_duration(*retry_after)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransitionError {}
@@ -0,0 +1,140 @@
+//! A state machine that tracks its state in the type. Only the transitions that are legal from a
+//! state exist as methods, so an illegal transition fails to compile instead of returning an `Err`.
+
+use crate::*;
+use std::marker::PhantomData;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Marks the state of a `Machine`. This is implemented for `Stored` and `Ready` only.
+pub trait Typestate: sealed::Sealed {
+    /// The state that holds the data while the machine is in this state.
+    type State: std::fmt::Debug;
+}
+
+/// The `Machine` is in the stored state.
+#[derive(Debug)]
+pub enum Stored {}
+
+/// The `Machine` is in the ready state.
+#[derive(Debug)]
+pub enum Ready {}
+
+impl sealed::Sealed for Stored {}
+impl sealed::Sealed for Ready {}
+
+impl Typestate for Stored {
+    type State = StoredState;
+}
+
+impl Typestate for Ready {
+    type State = ReadyState;
+}
+
+/// Wraps the `StoredState` or `ReadyState` that `S` refers to.
+#[derive(Debug)]
+pub struct Machine<S: Typestate> {
+    state: S::State,
+    _marker: PhantomData<S>,
+}
+
+impl<S: Typestate> Machine<S> {
+    fn wrap(state: S::State) -> Self {
+        Self {
+            state,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn state(&self) -> &S::State {
+        &self.state
+    }
+
+    pub fn into_state(self) -> S::State {
+        self.state
+    }
+}
+
+impl Machine<Stored> {
+    /// Starts in the stored state with the given ready count.
+    pub fn new(ready_count: u64) -> Self {
+        Self::wrap(StoredState::enter(StoredStateInputs::new(ready_count)))
+    }
+
+    pub fn ready(self) -> Machine<Ready> {
+        let StoredStateTransitionResult::Ready(state) = self.state.ready();
+        Machine::wrap(state)
+    }
+
+    /// Like `ready`, but hands back the machine unchanged when entering the ready state would
+    /// exceed the configured `max_ready_count` or overflow in `OverflowMode::Error`.
+    pub fn ready_checked(self) -> Result<Machine<Ready>, Self> {
+        match self.state.ready_checked() {
+            Ok(StoredStateTransitionResult::Ready(state)) => Ok(Machine::wrap(state)),
+            Err(state) => Err(Self::wrap(state)),
+        }
+    }
+}
+
+impl Machine<Ready> {
+    pub fn store(self) -> Machine<Stored> {
+        let ReadyStateStoreTransitionResult::Stored(state) = self.state.store();
+        Machine::wrap(state)
+    }
+}
+
+impl From<StoredState> for Machine<Stored> {
+    fn from(state: StoredState) -> Self {
+        Self::wrap(state)
+    }
+}
+
+impl From<ReadyState> for Machine<Ready> {
+    fn from(state: ReadyState) -> Self {
+        Self::wrap(state)
+    }
+}
+
+impl<S: Typestate> From<Machine<S>> for State
+where
+    State: From<S::State>,
+{
+    fn from(machine: Machine<S>) -> Self {
+        machine.state.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_and_store() {
+        let machine: Machine<Ready> = Machine::new(0).ready();
+        assert_eq!(1, machine.state().ready_count());
+        let machine: Machine<Stored> = machine.store();
+        assert_eq!(1, machine.state().ready_count());
+        assert_eq!(2, machine.state().transition_count());
+
+        let state = State::from(machine);
+        assert_eq!("stored", state.name());
+    }
+
+    #[test]
+    fn ready_checked_at_max_ready_count() {
+        let config = Config {
+            max_ready_count: Some(1),
+            ..Config::default()
+        };
+        let machine = Machine::from(StoredState::enter(StoredStateInputs {
+            config,
+            ..StoredStateInputs::new(1)
+        }));
+        let machine = machine
+            .ready_checked()
+            .expect_err("the ready count should not exceed the maximum");
+        assert_eq!(1, machine.state().ready_count());
+    }
+}
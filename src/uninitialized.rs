@@ -0,0 +1,264 @@
+use crate::*;
+pub use internal::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Span;
+
+mod internal {
+    use super::*;
+
+    /// The initial state, in which the state machine waits to be configured. It has no ready count
+    /// and can only be left through `initialize`.
+    #[derive(Debug)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    pub struct UninitializedState {
+        #[cfg_attr(feature = "serde", serde(skip, default = "system_clock"))]
+        clock: Arc<dyn Clock>,
+        #[cfg_attr(feature = "serde", serde(skip, default = "tracing_logger"))]
+        logger: Arc<dyn StateLogger>,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        transform: Option<Arc<dyn TransitionTransform>>,
+        #[cfg_attr(feature = "serde", serde(skip, default = "Timestamp::now"))]
+        uninitialized_start: Timestamp,
+        #[cfg_attr(feature = "serde", serde(skip, default = "Span::none"))]
+        span: Span,
+    }
+
+    impl UninitializedState {
+        pub fn enter(inputs: UninitializedStateInputs) -> Self {
+            let UninitializedStateInputs {
+                clock,
+                logger,
+                transform,
+            } = inputs;
+
+            let state = Self {
+                uninitialized_start: clock.timestamp(),
+                clock,
+                logger,
+                transform,
+                span: tracing::info_span!("uninitialized"),
+            };
+            state
+                .span
+                .in_scope(|| state.logger.state_entered("uninitialized", 0));
+            state
+        }
+
+        pub fn exit(self) -> UninitializedStateOutputs {
+            let Self {
+                clock,
+                logger,
+                transform,
+                uninitialized_start,
+                span,
+            } = self;
+
+            let time_in_state = clock
+                .timestamp()
+                .saturating_duration_since(uninitialized_start);
+            span.in_scope(|| logger.state_exited("uninitialized", time_in_state, 0));
+
+            UninitializedStateOutputs {
+                clock,
+                logger,
+                transform,
+                time_in_state,
+            }
+        }
+
+        pub fn clock(&self) -> &Arc<dyn Clock> {
+            &self.clock
+        }
+
+        /// The span that is open while we are in the uninitialized state. Enter it to associate
+        /// logs with this state.
+        pub fn span(&self) -> &Span {
+            &self.span
+        }
+
+        /// When we entered the uninitialized state.
+        pub fn entered_at(&self) -> Timestamp {
+            self.uninitialized_start
+        }
+
+        /// How long we have been in the uninitialized state so far.
+        pub fn elapsed(&self) -> Duration {
+            self.clock
+                .timestamp()
+                .saturating_duration_since(self.uninitialized_start)
+        }
+    }
+}
+
+/// This defines the inputs required to enter the UninitializedState.
+#[derive(Debug)]
+pub struct UninitializedStateInputs {
+    pub clock: Arc<dyn Clock>,
+    pub logger: Arc<dyn StateLogger>,
+    pub transform: Option<Arc<dyn TransitionTransform>>,
+}
+
+impl UninitializedStateInputs {
+    pub fn new() -> Self {
+        Self {
+            clock: system_clock(),
+            logger: tracing_logger(),
+            transform: None,
+        }
+    }
+}
+
+impl Default for UninitializedStateInputs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// This defines the outputs provided when leaving the UninitializedState.
+#[derive(Debug)]
+pub struct UninitializedStateOutputs {
+    pub clock: Arc<dyn Clock>,
+    pub logger: Arc<dyn StateLogger>,
+    pub transform: Option<Arc<dyn TransitionTransform>>,
+    /// How long we spent in the state that was exited.
+    pub time_in_state: Duration,
+}
+
+impl UninitializedState {
+    /// Constructs the real initial state from the configuration. An invalid configuration, see
+    /// `Config::validate`, hands back the state without calling `exit`.
+    pub fn initialize(self, config: Config) -> Result<StoredState, (Self, EnterError)> {
+        if let Err(error) = config.validate() {
+            return Err((self, error.into()));
+        }
+
+        let UninitializedStateOutputs {
+            clock,
+            logger,
+            transform,
+            time_in_state,
+        } = self.exit();
+
+        // Initializing counts as the first transition. The configuration has been validated, so
+        // entering can not fail.
+        Ok(StoredState::enter(StoredStateInputs {
+            ready_count: 0,
+            transition_count: 1,
+            overflowed: false,
+            clock,
+            logger,
+            transform,
+            config,
+            from: Some("uninitialized"),
+            last_request_id: None,
+            time_in_previous: time_in_state,
+            now: None,
+        }))
+    }
+
+    pub fn error(self, reason: String, recoverable: bool) -> ErrorState {
+        let UninitializedStateOutputs {
+            clock,
+            logger,
+            transform,
+            time_in_state,
+        } = self.exit();
+
+        ErrorState::enter(ErrorStateInputs {
+            ready_count: 0,
+            transition_count: 1,
+            overflowed: false,
+            clock,
+            logger,
+            transform,
+            config: Config::default(),
+            from: Some("uninitialized"),
+            last_request_id: None,
+            time_in_previous: time_in_state,
+            reason,
+            recoverable,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initialize_lands_in_stored() {
+        let config = Config {
+            step: 2,
+            ..Config::default()
+        };
+        let state = UninitializedState::enter(UninitializedStateInputs::new())
+            .initialize(config)
+            .expect("the configuration should be valid");
+        assert_eq!(0, state.ready_count());
+        assert_eq!(1, state.transition_count());
+        assert_eq!(2, state.config().step);
+        assert_eq!(Some("uninitialized"), state.previous());
+    }
+
+    #[test]
+    fn reject_operations_before_initializing() {
+        let (state, error) = State::default()
+            .ready()
+            .expect_err("can not ready before initializing");
+        assert_eq!("uninitialized", state.name());
+        assert!(matches!(
+            error,
+            TransitionError::Illegal {
+                valid_operations: &["initialize"],
+                ..
+            }
+        ));
+        let (state, _) = state
+            .store()
+            .expect_err("can not store before initializing");
+        let state = state
+            .initialize(Config::default())
+            .expect("should be able to initialize");
+        assert_eq!("stored", state.name());
+        state
+            .initialize(Config::default())
+            .expect_err("can not initialize twice");
+    }
+
+    #[test]
+    fn initialize_rejects_invalid_config() {
+        let config = Config {
+            step: 0,
+            ..Config::default()
+        };
+        let (state, error) = UninitializedState::enter(UninitializedStateInputs::new())
+            .initialize(config.clone())
+            .expect_err("a step of 0 should be rejected");
+        assert_eq!(EnterError::InvalidConfig(ConfigError::ZeroStep), error);
+
+        let (state, error) = State::Uninitialized(state)
+            .initialize(config.clone())
+            .expect_err("a step of 0 should be rejected");
+        assert_eq!("uninitialized", state.name());
+        assert_eq!(
+            TransitionError::InvalidConfig {
+                operation: "initialize",
+                state: "uninitialized",
+                error: EnterError::InvalidConfig(ConfigError::ZeroStep),
+            },
+            error
+        );
+
+        let mut machine = StateMachine::new(state);
+        let error = machine
+            .try_initialize(config)
+            .expect_err("a step of 0 should be rejected");
+        assert_eq!("initialize", error.operation());
+        assert_eq!("uninitialized", machine.current().name());
+        machine
+            .try_initialize(Config::default())
+            .expect("a valid configuration should be accepted afterwards");
+    }
+}
@@ -0,0 +1,14 @@
+// The states hide their fields so that the only way to leave a state is through `exit`. These
+// tests make sure that refactors do not accidentally make the fields reachable. They also check
+// that `Machine<S>` only has the transitions that are legal from `S`, and that an observer can not
+// transition the `StateMachine` that notifies it.
+//
+// The compiler suggests the constructors of a state in its errors, so the expected output has to be
+// updated with `TRYBUILD=overwrite` when adding one. The `testing` feature adds constructors, and
+// the states do not exist without the `std` feature.
+#[cfg(all(feature = "std", not(feature = "testing")))]
+#[test]
+fn exit_can_not_be_bypassed() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/compile_fail/*.rs");
+}
@@ -0,0 +1,10 @@
+use plain_state_machine_hype_train::ReadyState;
+
+fn main() {
+    // Building the state directly would skip `enter`.
+    let state = ReadyState {
+        ready_count: 1,
+        transition_count: 0,
+    };
+    println!("{state:?}");
+}
@@ -0,0 +1,8 @@
+use plain_state_machine_hype_train::{StoredState, StoredStateInputs};
+
+fn main() {
+    let state = StoredState::enter(StoredStateInputs::new(0));
+    // Taking the fields out of the state would skip `exit`.
+    let StoredState { ready_count, .. } = state;
+    println!("{ready_count}");
+}
@@ -0,0 +1,21 @@
+use plain_state_machine_hype_train::{StateMachine, TransitionObserver};
+
+struct NestedObserver<'a> {
+    machine: &'a mut StateMachine,
+}
+
+impl TransitionObserver for NestedObserver<'_> {
+    fn on_enter(&self, _to: &'static str) {
+        // Observers are notified through `&self`, so they can not start a nested transition.
+        let _ = self.machine.try_store();
+    }
+}
+
+fn main() {
+    // Nor can the machine be handed an observer that borrows it.
+    let mut machine = StateMachine::default();
+    let observer = NestedObserver {
+        machine: &mut machine,
+    };
+    machine.add_observer(Box::new(observer));
+}
@@ -0,0 +1,7 @@
+use plain_state_machine_hype_train::typestate::{Machine, Stored};
+
+fn main() {
+    // A machine in the stored state has no `store` transition.
+    let machine: Machine<Stored> = Machine::new(0);
+    let _ = machine.store();
+}